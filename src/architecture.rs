@@ -1,9 +1,28 @@
+use std::fmt;
+
 pub type CC = u8;
 
 pub const N : CC = 4;
 pub const Z : CC = 2;
 pub const P : CC = 1;
 
+// Memory map: each region runs up to the start of the next
+
+pub const USER_CODE : u16 = 0x0000;
+pub const USER_DATA : u16 = 0x2000;
+pub const OS_CODE   : u16 = 0x8000;
+pub const OS_DATA   : u16 = 0xA000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Region { UserCode, UserData, OSCode, OSData }
+
+pub fn region(addr: u16) -> Region {
+  if addr >= OS_DATA { Region::OSData }
+  else if addr >= OS_CODE { Region::OSCode }
+  else if addr >= USER_DATA { Region::UserData }
+  else { Region::UserCode }
+}
+
 pub type RName = usize;
 
 pub const R0 : RName = 0;
@@ -109,3 +128,50 @@ pub fn partial_cast<A,B,C,D>(insn: &InsnGen<A,B>) -> InsnGen<C,D> {
     &InsnGen::TRAP(u)           => InsnGen::TRAP(u)
   }
 }
+
+// Condition codes as they appear in branch mnemonics
+pub fn cc_suffix(cc: CC) -> String {
+  let mut suffix = String::new();
+  if cc & N != 0 { suffix.push('n') }
+  if cc & Z != 0 { suffix.push('z') }
+  if cc & P != 0 { suffix.push('p') }
+  suffix
+}
+
+// Canonical assembly text, with PC-relative offsets left as immediates
+impl fmt::Display for Insn {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      InsnGen::NOP                 => write!(f, "NOP"),
+      InsnGen::BR(cc, i)           => write!(f, "BR{} #{}", cc_suffix(cc), i.value),
+      InsnGen::ADD(rd, rs, rt)     => write!(f, "ADD R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::MUL(rd, rs, rt)     => write!(f, "MUL R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::SUB(rd, rs, rt)     => write!(f, "SUB R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::DIV(rd, rs, rt)     => write!(f, "DIV R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::ADDi(rd, rs, i)     => write!(f, "ADD R{}, R{}, #{}", rd, rs, i.value),
+      InsnGen::CMP(rd, rt)         => write!(f, "CMP R{}, R{}", rd, rt),
+      InsnGen::CMPu(rd, rt)        => write!(f, "CMPU R{}, R{}", rd, rt),
+      InsnGen::CMPi(rd, i)         => write!(f, "CMPI R{}, #{}", rd, i.value),
+      InsnGen::CMPiu(rd, u)        => write!(f, "CMPIU R{}, #{}", rd, u.value),
+      InsnGen::JSR(i)              => write!(f, "JSR #{}", i.value),
+      InsnGen::JSRr(rs)            => write!(f, "JSRR R{}", rs),
+      InsnGen::AND(rd, rs, rt)     => write!(f, "AND R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::NOT(rd, rs)         => write!(f, "NOT R{}, R{}", rd, rs),
+      InsnGen::OR(rd, rs, rt)      => write!(f, "OR R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::XOR(rd, rs, rt)     => write!(f, "XOR R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::ANDi(rd, rs, i)     => write!(f, "AND R{}, R{}, #{}", rd, rs, i.value),
+      InsnGen::LDR(rd, rs, i)      => write!(f, "LDR R{}, R{}, #{}", rd, rs, i.value),
+      InsnGen::STR(rd, rs, i)      => write!(f, "STR R{}, R{}, #{}", rd, rs, i.value),
+      InsnGen::RTI                 => write!(f, "RTI"),
+      InsnGen::CONST(rd, i)        => write!(f, "CONST R{}, #{}", rd, i.value),
+      InsnGen::SLL(rd, rs, u)      => write!(f, "SLL R{}, R{}, #{}", rd, rs, u.value),
+      InsnGen::SRA(rd, rs, u)      => write!(f, "SRA R{}, R{}, #{}", rd, rs, u.value),
+      InsnGen::SRL(rd, rs, u)      => write!(f, "SRL R{}, R{}, #{}", rd, rs, u.value),
+      InsnGen::MOD(rd, rs, rt)     => write!(f, "MOD R{}, R{}, R{}", rd, rs, rt),
+      InsnGen::JMPr(rs)            => write!(f, "JMPR R{}", rs),
+      InsnGen::JMP(i)              => write!(f, "JMP #{}", i.value),
+      InsnGen::HICONST(rd, u)      => write!(f, "HICONST R{}, x{:02X}", rd, u.value),
+      InsnGen::TRAP(u)             => write!(f, "TRAP x{:02X}", u.value)
+    }
+  }
+}
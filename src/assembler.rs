@@ -1,5 +1,7 @@
+use std::cmp::max;
 use std::convert::From;
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
@@ -8,8 +10,76 @@ use std::fs::OpenOptions;
 use architecture::*;
 use assm_data::*;
 
+// Where a statement came from.  The column is that of the statement's
+// last operand, which is where range errors are reported.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub column: usize
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity { Error, Warning }
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    // The operators the parser expected where it failed
+    Syntax(Vec<String>),
+    DuplicateLabel(Label),
+    UndefinedLabel(Label),
+    // A statement in the wrong section
+    WrongSection(Section),
+    // A jump to a data label
+    DataTarget(Label),
+    // A JSR target not on a 16-word boundary
+    Misaligned(Label, u16),
+    // The value and its allowed range
+    OutOfRange(i32, i32, i32),
+    // Words placed past the end of memory
+    AddressOverflow
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::Syntax(ref expected) =>
+                write!(f, "syntax error, expected one of {}", expected.join(", ")),
+            Problem::DuplicateLabel(ref l) => write!(f, "label {} is already defined", l),
+            Problem::UndefinedLabel(ref l) => write!(f, "label {} is not defined", l),
+            Problem::WrongSection(Section::CODE) => write!(f, "only allowed in a .CODE section"),
+            Problem::WrongSection(Section::DATA) => write!(f, "only allowed in a .DATA section"),
+            Problem::DataTarget(ref l) => write!(f, "jump target {} is a data label", l),
+            Problem::Misaligned(ref l, addr) =>
+                write!(f, "JSR target {} at x{:04X} is not a multiple of 16", l, addr),
+            Problem::OutOfRange(value, min, max) =>
+                write!(f, "value {} out of range [{}, {}]", value, min, max),
+            Problem::AddressOverflow => write!(f, "address overflows past xFFFF")
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssmDiagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub problem: Problem
+}
+
+impl AssmDiagnostic {
+    pub fn error(span: &Span, problem: Problem) -> AssmDiagnostic {
+        AssmDiagnostic{ span: span.clone(), severity: Severity::Error, problem: problem }
+    }
+}
+
 #[derive(Debug)]
-pub enum AssmError { IoError(io::Error), ParseError(lc4_grammar::ParseError) }
+pub enum AssmError { IoError(io::Error), ParseErrors(Vec<AssmDiagnostic>) }
 
 impl From<io::Error> for AssmError {
     fn from(err: io::Error) -> AssmError {
@@ -17,54 +87,179 @@ impl From<io::Error> for AssmError {
     }
 }
 
-impl From<lc4_grammar::ParseError> for AssmError {
-    fn from(err: lc4_grammar::ParseError) -> AssmError {
-        AssmError::ParseError(err)
-    }
-}
-
 pub type LInsn = InsnGen<Label, Label>;
 
 #[derive(Debug)]
 pub enum Assm {
     LABEL(Label),
-    Insn(LInsn),
+    // The immediate operand as written, if the instruction has one
+    Insn(LInsn, Option<i32>),
     RET,
     LEA(RName, Label),
     LC(RName, Label),
     CODE,
     DATA,
-    ADDR(UIMM16),
+    ADDR(i32),
     FALIGN,
-    FILL(IMM16),
+    FILL(i32),
     STRINGZ(String),
-    BLKW(UIMM16),
-    LCONST(Label, IMM16),
-    LUCONST(Label, UIMM16)
+    BLKW(i32),
+    LCONST(Label, i32),
+    LUCONST(Label, i32)
 }    
 
+#[derive(Debug)]
+pub struct Located {
+    pub assm: Assm,
+    pub span: Span
+}
+
 peg_file! lc4_grammar("grammar/lc4.pegjs");
 
-pub fn read_assembly_file(filename: &str) -> Result<Vec<Assm>, AssmError> {
+// Literals stay i32 until they have been range checked. Digits too long
+// for an i32 saturate, so they still fail the check.
+pub fn parse_digits(digits: &str, radix: u32) -> i32 {
+    i32::from_str_radix(digits, radix).unwrap_or(i32::max_value())
+}
+
+// 1-based column where the last operand starts, ignoring any comment
+fn last_operand_column(line: &str) -> usize {
+    let mut quote: Option<char> = None;
+    let mut separated = true;
+    let mut start = 0;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(q), c) => if c == q { quote = None },
+            (None, ';') => break,
+            (None, ' ') | (None, '\t') | (None, ',') => separated = true,
+            (None, c) => {
+                if separated { start = i; separated = false }
+                if c == '"' || c == '\'' { quote = Some(c) }
+            }
+        }
+    }
+    start + 1
+}
+
+// Every line is parsed, so that all syntax errors are reported together
+pub fn read_assembly_file(filename: &str) -> Result<Vec<Located>, AssmError> {
     let mut options = OpenOptions::new();
     options.read(true);
     let file = try!(options.open(&Path::new(filename)));
     let reader = BufReader::new(file);
     let mut assms = Vec::new();
-    for line in reader.lines() {
+    let mut errors = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        let line = line.trim_right();
+        match lc4_grammar::line(line) {
+            Ok(Some(assm)) => {
+                let span = Span{ file: filename.to_string(), line: n + 1, column: last_operand_column(line) };
+                assms.push(Located{ assm: assm, span: span })
+            },
+            Ok(None) => (),
+            Err(err) => {
+                let span = Span{ file: filename.to_string(), line: n + 1, column: err.column };
+                let mut expected: Vec<String> = err.expected.iter().map(|e| e.to_string()).collect();
+                expected.sort();
+                errors.push(AssmDiagnostic::error(&span, Problem::Syntax(expected)))
+            }
+        }
+    }
+    if errors.is_empty() { Ok(assms) } else { Err(AssmError::ParseErrors(errors)) }
+}
+
+pub fn pad16(addr: u16) -> Option<u16> {
+    let padded = addr & 0xFFF0;
+    if padded < addr { padded.checked_add(0x10) } else { Some(padded) }
+}
+
+// Moves addr past words placed there, unless they would run past xFFFF
+fn advance(diagnostics: &mut Vec<AssmDiagnostic>, span: &Span, addr: &mut u16, words: usize) -> bool {
+    if *addr as usize + words > 0xFFFF {
+        diagnostics.push(AssmDiagnostic::error(span, Problem::AddressOverflow));
+        false
+    } else {
+        *addr += words as u16;
+        true
+    }
+}
+
+fn check_range(span: &Span, value: i32, min: i32, max: i32) -> Result<(), AssmDiagnostic> {
+    if value < min || value > max {
+        Err(AssmDiagnostic::error(span, Problem::OutOfRange(value, min, max)))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_signed(span: &Span, value: i32, bits: u32) -> Result<(), AssmDiagnostic> {
+    check_range(span, value, -(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+}
+
+fn check_unsigned(span: &Span, value: i32, bits: u32) -> Result<(), AssmDiagnostic> {
+    check_range(span, value, 0, (1 << bits) - 1)
+}
+
+fn check_section(span: &Span, section: Section, expected: Section) -> Result<(), AssmDiagnostic> {
+    if section == expected {
+        Ok(())
+    } else {
+        Err(AssmDiagnostic::error(span, Problem::WrongSection(expected)))
+    }
+}
+
+// Checks the immediate as written in the source, before it was narrowed
+// into the instruction; label offsets are checked once they are known
+fn check_immediate(span: &Span, insn: &LInsn, value: i32) -> Result<(), AssmDiagnostic> {
+    match *insn {
+        InsnGen::ADDi(..) | InsnGen::ANDi(..) => check_signed(span, value, 5),
+        InsnGen::LDR(..) | InsnGen::STR(..) => check_signed(span, value, 6),
+        InsnGen::CMPi(..) => check_signed(span, value, 7),
+        InsnGen::CONST(..) => check_signed(span, value, 9),
+        InsnGen::CMPiu(..) => check_unsigned(span, value, 7),
+        InsnGen::SLL(..) | InsnGen::SRA(..) | InsnGen::SRL(..) => check_unsigned(span, value, 4),
+        InsnGen::HICONST(..) | InsnGen::TRAP(..) => check_unsigned(span, value, 8),
+        _ => Ok(())
+    }
+}
+
+// Jump targets must be code labels
+fn code_label(span: &Span, labels: &HashMap<Label, (Section, u16)>, target: &Label)
+              -> Result<u16, AssmDiagnostic> {
+    match labels.get(target) {
+        Some(&(Section::CODE, label_addr)) => Ok(label_addr),
+        Some(&(Section::DATA, _)) => Err(AssmDiagnostic::error(span, Problem::DataTarget(target.clone()))),
+        None => Err(AssmDiagnostic::error(span, Problem::UndefinedLabel(target.clone())))
+    }
+}
+
+// Data labels are relative to the start of the data section
+fn label_address(span: &Span, label: (Section, u16), base_data_addr: u16) -> Result<u16, AssmDiagnostic> {
+    match label {
+        (Section::CODE, label_addr) => Ok(label_addr),
+        (Section::DATA, label_addr) => match label_addr.checked_add(base_data_addr) {
+            Some(label_addr) => Ok(label_addr),
+            None => Err(AssmDiagnostic::error(span, Problem::AddressOverflow))
+        }
+    }
+}
 
-        assms.push(try!(lc4_grammar::assm(&try!(line).trim()[..])))
+// Diagnostics are collected rather than returned early, so one run reports
+// every problem in the file
+fn report(diagnostics: &mut Vec<AssmDiagnostic>, result: Result<(), AssmDiagnostic>) {
+    if let Err(diagnostic) = result {
+        diagnostics.push(diagnostic)
     }
-    Ok(assms)
 }
 
-pub fn pad16(addr: u16) -> u16 {
-    let mut padded = addr & 0xFFF0;
-    if padded < addr { padded += 0x10; }
-    padded
+// The passes find problems out of order, so report them in source order
+fn in_source_order(mut diagnostics: Vec<AssmDiagnostic>) -> Vec<AssmDiagnostic> {
+    diagnostics.sort_by(|a, b| (a.span.line, a.span.column).cmp(&(b.span.line, b.span.column)));
+    diagnostics
 }
 
-pub fn assemble(assm_lines: Vec<Assm>) -> AssmData<Mem> {
+pub fn assemble(assm_lines: Vec<Located>) -> Result<AssmData<Mem>, Vec<AssmDiagnostic>> {
 
     let mut section: Section = Section::CODE;
 
@@ -73,156 +268,230 @@ pub fn assemble(assm_lines: Vec<Assm>) -> AssmData<Mem> {
 
     let mut addr_labels: HashMap<Label, (Section, u16)> = HashMap::new();
     let mut value_labels: HashMap<Label, i16> = HashMap::new();
+    let mut diagnostics: Vec<AssmDiagnostic> = Vec::new();
+    let mut last_moved: Option<&Span> = None;
 
     println!("First pass to place labels");
 
-    for &ref assm in assm_lines.iter() {
-        match assm {
+    for &ref located in assm_lines.iter() {
+        let span = &located.span;
+        let before = (code_addr, data_addr);
+        match &located.assm {
 
             // Instructions and Pseudo-Instructions
-            &Assm::Insn(_) => {
-                assert!(section == Section::CODE);
-                code_addr += 1
+            &Assm::Insn(_, _) => {
+                report(&mut diagnostics, check_section(span, section, Section::CODE));
+                advance(&mut diagnostics, span, &mut code_addr, 1);
             },
             &Assm::RET => {
-                assert!(section == Section::CODE);
-                code_addr += 1
+                report(&mut diagnostics, check_section(span, section, Section::CODE));
+                advance(&mut diagnostics, span, &mut code_addr, 1);
             },
             &Assm::LEA(_, _) => {
-                assert!(section == Section::CODE);
-                code_addr += 1
+                report(&mut diagnostics, check_section(span, section, Section::CODE));
+                advance(&mut diagnostics, span, &mut code_addr, 1);
             },
             &Assm::LC(_, _) => {
-                assert!(section == Section::CODE);
-                code_addr += 1
+                report(&mut diagnostics, check_section(span, section, Section::CODE));
+                advance(&mut diagnostics, span, &mut code_addr, 1);
             },
 
             // Assembler Directives
             &Assm::LABEL(ref l) => {
                 if addr_labels.contains_key(l) {
-                    panic!("Cannot have duplicate labels")
+                    diagnostics.push(AssmDiagnostic::error(span, Problem::DuplicateLabel(l.clone())));
+                    continue
                 }
-                addr_labels.insert(l.clone(), (section, code_addr));
+                let label_addr = match section {
+                    Section::CODE => code_addr,
+                    Section::DATA => data_addr
+                };
+                addr_labels.insert(l.clone(), (section, label_addr));
             },
-
             &Assm::CODE => section = Section::CODE,
             &Assm::DATA => section = Section::DATA,
 
-            &Assm::ADDR(ref u) => 
+            &Assm::ADDR(n) => {
+                report(&mut diagnostics, check_unsigned(span, n, 16));
                 match section {
-                    Section::CODE => code_addr = u.value,
-                    Section::DATA => data_addr = u.value
-                },
+                    Section::CODE => code_addr = n as u16,
+                    Section::DATA => data_addr = n as u16
+                }
+            },
 
                 &Assm::FALIGN => {
-                    match section {
-                        Section::CODE => code_addr = pad16(code_addr),
-                        Section::DATA => data_addr = pad16(data_addr)
+                    let addr = match section {
+                        Section::CODE => &mut code_addr,
+                        Section::DATA => &mut data_addr
+                    };
+                    match pad16(*addr) {
+                        Some(padded) => *addr = padded,
+                        None => diagnostics.push(AssmDiagnostic::error(span, Problem::AddressOverflow))
                     }
                 },
 
                 &Assm::FILL(_) => {
-                    assert!(section == Section::DATA);
-                    data_addr += 1
+                    report(&mut diagnostics, check_section(span, section, Section::DATA));
+                    advance(&mut diagnostics, span, &mut data_addr, 1);
                 },
                 &Assm::STRINGZ(ref s) => {
-                    assert!(section == Section::DATA);
-                    data_addr += s.len() as u16
+                    report(&mut diagnostics, check_section(span, section, Section::DATA));
+                    // One word per character and a terminating zero
+                    advance(&mut diagnostics, span, &mut data_addr, s.chars().count() + 1);
                 },
 
-                &Assm::BLKW(ref u) => {
-                    match section {
-                        Section::CODE => code_addr += u.value,
-                        Section::DATA => data_addr += u.value
-                    }
+                &Assm::BLKW(n) => {
+                    report(&mut diagnostics, check_unsigned(span, n, 16));
+                    let addr = match section {
+                        Section::CODE => &mut code_addr,
+                        Section::DATA => &mut data_addr
+                    };
+                    advance(&mut diagnostics, span, addr, n as u16 as usize);
                 },
 
-                &Assm::LCONST(ref l, ref i) => {
+                &Assm::LCONST(ref l, n) => {
+                    report(&mut diagnostics, check_signed(span, n, 16));
                     if value_labels.contains_key(l) {
-                        panic!("Cannot have duplicate labels")
+                        diagnostics.push(AssmDiagnostic::error(span, Problem::DuplicateLabel(l.clone())))
                     }
-                    value_labels.insert(l.clone(), i.value);
+                    value_labels.insert(l.clone(), n as i16);
                 }
-            &Assm::LUCONST(ref l, ref u) => {
+            &Assm::LUCONST(ref l, n) => {
+                report(&mut diagnostics, check_unsigned(span, n, 16));
                 if value_labels.contains_key(l) {
-                    panic!("Cannot have duplicate labels")
+                    diagnostics.push(AssmDiagnostic::error(span, Problem::DuplicateLabel(l.clone())))
                 }
-                value_labels.insert(l.clone(), u.value as i16);
+                value_labels.insert(l.clone(), n as u16 as i16);
             }
         }
+        if (code_addr, data_addr) != before {
+            last_moved = Some(span)
+        }
     }
 
     println!("Second pass to place instructions");
 
-    let base_data_addr = pad16(code_addr);
-    let base_heap_addr = pad16(base_data_addr + data_addr);
+    // Data follows the code, but no lower than USER_DATA, since user loads
+    // and stores outside the data region fault.  The heap follows the data.
+    let layout = pad16(code_addr).map(|code_end| max(code_end, USER_DATA)).and_then(|base_data_addr| {
+        base_data_addr.checked_add(data_addr).and_then(pad16).map(|base_heap_addr| (base_data_addr, base_heap_addr))
+    });
+    let (base_data_addr, base_heap_addr) = match layout {
+        Some(layout) => layout,
+        None => {
+            // Blame the statement that last moved an address, unless one
+            // already ran off the end of memory
+            if !diagnostics.iter().any(|d| d.problem == Problem::AddressOverflow) {
+                if let Some(span) = last_moved {
+                    diagnostics.push(AssmDiagnostic::error(span, Problem::AddressOverflow))
+                }
+            }
+            return Err(in_source_order(diagnostics))
+        }
+    };
     let mut memory: Memory<Mem> = box [Mem::DATA(0);0x10000];
     let mut addr: u16 = 0;
 
     println!("heap {}", base_heap_addr);
 
-    for &ref assm in assm_lines.iter() {
-        println!("PC {} Insn {:?}", addr, assm);
+    for &ref located in assm_lines.iter() {
+        let span = &located.span;
+        println!("PC {} Insn {:?}", addr, located.assm);
 
-        match assm {
+        match &located.assm {
 
             &Assm::LABEL(ref target) => {
-                let (label_section, label_addr) = addr_labels[&target.clone()];
-                match label_section {
-                    Section::CODE => addr = label_addr,
-                    Section::DATA => addr = label_addr + base_data_addr
+                // Every label was placed in the first pass
+                match label_address(span, addr_labels[target], base_data_addr) {
+                    Ok(label_addr) => addr = label_addr,
+                    Err(diagnostic) => diagnostics.push(diagnostic)
                 }
             },
 
-            &Assm::Insn(InsnGen::BR(cc, ref target)) => {
-                let (section, label_addr) = addr_labels[&target.clone()];
-                assert!(section == Section::CODE);
-                memory[addr as usize] = Mem::CODE(InsnGen::BR(cc, IMM9{value: (label_addr - (addr + 1)) as i16}));
-                addr += 1
+            &Assm::Insn(InsnGen::BR(cc, ref target), _) => {
+                let offset = code_label(span, &addr_labels, target).and_then(|label_addr| {
+                    let offset = label_addr as i32 - (addr as i32 + 1);
+                    check_signed(span, offset, 9).map(|_| offset)
+                });
+                match offset {
+                    Ok(offset) => memory[addr as usize] = Mem::CODE(InsnGen::BR(cc, IMM9{value: offset as i16})),
+                    Err(diagnostic) => diagnostics.push(diagnostic)
+                }
+                advance(&mut diagnostics, span, &mut addr, 1);
             },
-            &Assm::Insn(InsnGen::JSR(ref target)) => {
-                let (section, label_addr) = addr_labels[&target.clone()];
-                assert!(section == Section::CODE);
-                memory[addr as usize] = Mem::CODE(InsnGen::JSR(IMM11{value: (label_addr - (addr & 0x8000)) as i16 >> 4}));
-                addr += 1
+            &Assm::Insn(InsnGen::JSR(ref target), _) => {
+                let offset = code_label(span, &addr_labels, target).and_then(|label_addr| {
+                    if label_addr & 0xF != 0 {
+                        return Err(AssmDiagnostic::error(span, Problem::Misaligned(target.clone(), label_addr)))
+                    }
+                    let offset = (label_addr as i32 - (addr & 0x8000) as i32) >> 4;
+                    check_signed(span, offset, 11).map(|_| offset)
+                });
+                match offset {
+                    Ok(offset) => memory[addr as usize] = Mem::CODE(InsnGen::JSR(IMM11{value: offset as i16})),
+                    Err(diagnostic) => diagnostics.push(diagnostic)
+                }
+                advance(&mut diagnostics, span, &mut addr, 1);
             },
-            &Assm::Insn(InsnGen::JMP(ref target)) => {
-                let (section, label_addr) = addr_labels[&target.clone()];
-                assert!(section == Section::CODE);
-                memory[addr as usize] = Mem::CODE(InsnGen::JMP(IMM11{value: (label_addr - (addr + 1)) as i16}));
-                addr += 1
+            &Assm::Insn(InsnGen::JMP(ref target), _) => {
+                let offset = code_label(span, &addr_labels, target).and_then(|label_addr| {
+                    let offset = label_addr as i32 - (addr as i32 + 1);
+                    check_signed(span, offset, 11).map(|_| offset)
+                });
+                match offset {
+                    Ok(offset) => memory[addr as usize] = Mem::CODE(InsnGen::JMP(IMM11{value: offset as i16})),
+                    Err(diagnostic) => diagnostics.push(diagnostic)
+                }
+                advance(&mut diagnostics, span, &mut addr, 1);
             },
 
-            &Assm::Insn(ref insn) => {
+            &Assm::Insn(ref insn, literal) => {
+                if let Some(value) = literal {
+                    report(&mut diagnostics, check_immediate(span, insn, value))
+                }
                 memory[addr as usize] = Mem::CODE(partial_cast(insn));
-                addr += 1
+                advance(&mut diagnostics, span, &mut addr, 1);
             }
 
             &Assm::RET => {
                 memory[addr as usize] = Mem::CODE(InsnGen::JMPr(R7));
-                addr += 1
+                advance(&mut diagnostics, span, &mut addr, 1);
             },
 
             &Assm::LEA(rd, ref target) => {
-                let (section, label_addr) = addr_labels[&target.clone()];
-                let label_addr = match section {
-                    Section::CODE => label_addr,
-                    Section::DATA => label_addr + base_data_addr
+                let at = addr as usize;
+                if !advance(&mut diagnostics, span, &mut addr, 2) {
+                    continue
+                }
+                let label_addr = match addr_labels.get(target) {
+                    Some(&label) => label_address(span, label, base_data_addr),
+                    None => Err(AssmDiagnostic::error(span, Problem::UndefinedLabel(target.clone())))
                 };
-                let low = IMM9{value: label_addr as i16 & 0x01FF};
-                let high = UIMM8{value: label_addr >> 8};
-                memory[addr as usize] = Mem::CODE(InsnGen::CONST(rd, low));
-                memory[addr as usize + 1] = Mem::CODE(InsnGen::HICONST(rd, high));
-                addr += 2
+                match label_addr {
+                    Ok(label_addr) => {
+                        let low = IMM9{value: label_addr as i16 & 0x01FF};
+                        let high = UIMM8{value: label_addr >> 8};
+                        memory[at] = Mem::CODE(InsnGen::CONST(rd, low));
+                        memory[at + 1] = Mem::CODE(InsnGen::HICONST(rd, high));
+                    },
+                    Err(diagnostic) => diagnostics.push(diagnostic)
+                }
             },
 
             &Assm::LC(rd, ref target) => {
-                let label_value = value_labels[&target.clone()];
-                let low = IMM9{value: label_value & 0x01FF};
-                let high = UIMM8{value: label_value as u16 >> 8};
-                memory[addr as usize] = Mem::CODE(InsnGen::CONST(rd, low));
-                memory[addr as usize + 1] = Mem::CODE(InsnGen::HICONST(rd, high));
-                addr += 2
+                let at = addr as usize;
+                if !advance(&mut diagnostics, span, &mut addr, 2) {
+                    continue
+                }
+                match value_labels.get(target) {
+                    Some(&label_value) => {
+                        let low = IMM9{value: label_value & 0x01FF};
+                        let high = UIMM8{value: label_value as u16 >> 8};
+                        memory[at] = Mem::CODE(InsnGen::CONST(rd, low));
+                        memory[at + 1] = Mem::CODE(InsnGen::HICONST(rd, high));
+                    },
+                    None => diagnostics.push(AssmDiagnostic::error(span, Problem::UndefinedLabel(target.clone())))
+                }
             }
 
             &Assm::CODE => (),
@@ -230,12 +499,23 @@ pub fn assemble(assm_lines: Vec<Assm>) -> AssmData<Mem> {
             &Assm::ADDR(_) => (),
             &Assm::FALIGN => (),
 
-            &Assm::FILL(i) => {
-                memory[addr as usize] = Mem::DATA(i.value);
-                addr += 1
+            &Assm::FILL(n) => {
+                // Either a signed or an unsigned word
+                report(&mut diagnostics, check_range(span, n, -0x8000, 0xFFFF));
+                memory[addr as usize] = Mem::DATA(n as i16);
+                advance(&mut diagnostics, span, &mut addr, 1);
             },
 
-            &Assm::STRINGZ(_) => panic!("Not implemented"),
+            &Assm::STRINGZ(ref s) => {
+                let at = addr as usize;
+                let words = s.chars().count() + 1;
+                if advance(&mut diagnostics, span, &mut addr, words) {
+                    for (i, c) in s.chars().enumerate() {
+                        memory[at + i] = Mem::DATA(c as i16);
+                    }
+                    memory[at + words - 1] = Mem::DATA(0);
+                }
+            },
 
             &Assm::BLKW(_) => (),
             &Assm::LCONST(_,_) => (),
@@ -243,9 +523,169 @@ pub fn assemble(assm_lines: Vec<Assm>) -> AssmData<Mem> {
         }
     }
 
-    AssmData{
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(in_source_order(diagnostics))
+    }
+
+    // Report label addresses as absolute for the symbol table; the second
+    // pass already checked that data labels fit
+    let labels = addr_labels.into_iter().map(|(l, (section, label_addr))| {
+        match section {
+            Section::CODE => (l, (section, label_addr)),
+            Section::DATA => (l, (section, label_addr + base_data_addr))
+        }
+    }).collect();
+
+    Ok(AssmData{
         memory: memory,
-        labels: addr_labels,
+        labels: labels,
         heap: base_heap_addr
+    })
+}
+
+#[test]
+fn comment_unit_tests () {
+    assert!(lc4_grammar::line("").unwrap().is_none());
+    assert!(lc4_grammar::line("  ; just a comment").unwrap().is_none());
+    match lc4_grammar::line("\tADD R1, R1, R2 ; trailing comment") {
+        Ok(Some(Assm::Insn(InsnGen::ADD(R1, R1, R2), None))) => (),
+        other => panic!("{:?}", other)
+    }
+    match lc4_grammar::line("CONST R0, 0") {
+        Ok(Some(Assm::Insn(InsnGen::CONST(R0, IMM9{value: 0}), Some(0)))) => (),
+        other => panic!("{:?}", other)
+    }
+    match lc4_grammar::line("LOOP") {
+        Ok(Some(Assm::LABEL(ref l))) if l == "LOOP" => (),
+        other => panic!("{:?}", other)
+    }
+}
+
+#[test]
+fn branch_unit_tests () {
+    let cases = [("BRn", N), ("BRz", Z), ("BRp", P), ("BRnz", N|Z), ("BRnp", N|P),
+                 ("BRzp", Z|P), ("BRnzp", N|Z|P), ("BR", N|Z|P)];
+    for &(mnemonic, cc) in cases.iter() {
+        match lc4_grammar::line(&format!("{} LOOP", mnemonic)) {
+            Ok(Some(Assm::Insn(InsnGen::BR(parsed, ref l), None))) if parsed == cc && l == "LOOP" => (),
+            other => panic!("{}: {:?}", mnemonic, other)
+        }
+    }
+}
+
+#[test]
+fn literal_unit_tests () {
+    let imm = |text: &str| match lc4_grammar::line(&format!(".FILL {}", text)) {
+        Ok(Some(Assm::FILL(n))) => n,
+        other => panic!("{:?}", other)
+    };
+    assert!(imm("#-5") == -5);
+    assert!(imm("x7FFF") == 0x7FFF);
+    assert!(imm("0x10") == 16);
+    assert!(imm("xFFFF") == 0xFFFF);
+    assert!(imm("#70000") == 70000);
+    assert!(imm("x100000000") == i32::max_value());
+    assert!(imm("b1010") == 10);
+    assert!(imm("'A'") == 65);
+    assert!(imm("'\\n'") == 10);
+    assert!(imm("'\\''") == 39);
+}
+
+#[cfg(test)]
+fn located(line: usize, text: &str) -> Located {
+    Located{
+        assm: lc4_grammar::line(text).unwrap().unwrap(),
+        span: Span{ file: "test.asm".to_string(), line: line, column: last_operand_column(text) }
+    }
+}
+
+#[cfg(test)]
+fn problems(lines: &[&str]) -> Vec<(usize, Problem)> {
+    let assm_lines = lines.iter().enumerate().map(|(n, text)| located(n + 1, text)).collect();
+    match assemble(assm_lines) {
+        Ok(_) => Vec::new(),
+        Err(diagnostics) => diagnostics.into_iter().map(|d| (d.span.line, d.problem)).collect()
     }
 }
+
+#[test]
+fn range_unit_tests () {
+    assert!(last_operand_column("  ADD R1, R1, #100 ; too big") == 15);
+    assert!(last_operand_column("RTI") == 1);
+
+    assert!(problems(&["ADD R1, R1, #15"]).is_empty());
+    match assemble(vec![located(1, "ADD R1, R1, #100")]) {
+        Err(ref d) if d[0].span.column == 13 && d[0].problem == Problem::OutOfRange(100, -16, 15) => (),
+        other => panic!("{:?}", other.err())
+    }
+    assert!(problems(&["TRAP x100"]) == vec![(1, Problem::OutOfRange(256, 0, 255))]);
+
+    // Literals are checked as written, not after narrowing to 16 bits
+    assert!(problems(&["ADD R1, R1, #65535"]) == vec![(1, Problem::OutOfRange(65535, -16, 15))]);
+    assert!(problems(&["ADD R1, R1, xFFFF"]) == vec![(1, Problem::OutOfRange(65535, -16, 15))]);
+    assert!(problems(&["ADD R1, R1, #70000"]) == vec![(1, Problem::OutOfRange(70000, -16, 15))]);
+    assert!(problems(&["CONST R0, #65000"]) == vec![(1, Problem::OutOfRange(65000, -256, 255))]);
+    assert!(problems(&["TRAP #-1"]) == vec![(1, Problem::OutOfRange(-1, 0, 255))]);
+    assert!(problems(&[".DATA", ".FILL xFFFF", ".FILL #-32768"]).is_empty());
+    assert!(problems(&[".DATA", ".FILL #70000"]) == vec![(2, Problem::OutOfRange(70000, -0x8000, 0xFFFF))]);
+
+    let mut far = vec!["FAR"];
+    for _ in 0..300 { far.push("NOP") }
+    far.push("BRp FAR");
+    assert!(problems(&far) == vec![(302, Problem::OutOfRange(-301, -256, 255))]);
+}
+
+#[test]
+fn overflow_unit_tests () {
+    assert!(pad16(0x1234) == Some(0x1240) && pad16(0x1240) == Some(0x1240));
+    assert!(pad16(0xFFF1) == None);
+
+    assert!(problems(&[".ADDR xFFF0", ".BLKW #16"]) == vec![(2, Problem::AddressOverflow)]);
+    assert!(problems(&[".ADDR xFFFE", ".FALIGN"]) == vec![(2, Problem::AddressOverflow)]);
+    assert!(problems(&[".DATA", ".ADDR xFFFE", ".STRINGZ \"ab\""]) == vec![(3, Problem::AddressOverflow)]);
+
+    // Data fits on its own but not once placed after the code
+    assert!(problems(&[".DATA", ".BLKW xF000"]) == vec![(2, Problem::AddressOverflow)]);
+    assert!(problems(&[".DATA", ".ADDR xE000", ".FILL #1"]) == vec![(3, Problem::AddressOverflow)]);
+}
+
+#[test]
+fn diagnostic_unit_tests () {
+    let found = problems(&[
+        "START",
+        "ADD R1, R1, #100",
+        "JSR NOWHERE",
+        "START",
+        ".FILL #1",
+        ".DATA",
+        "TABLE",
+        "RTI",
+        ".CODE",
+        "JMP TABLE"
+    ]);
+    assert!(found == vec![
+        (2, Problem::OutOfRange(100, -16, 15)),
+        (3, Problem::UndefinedLabel("NOWHERE".to_string())),
+        (4, Problem::DuplicateLabel("START".to_string())),
+        (5, Problem::WrongSection(Section::DATA)),
+        (8, Problem::WrongSection(Section::CODE)),
+        (10, Problem::DataTarget("TABLE".to_string()))
+    ]);
+}
+
+#[test]
+fn stringz_unit_tests () {
+    match lc4_grammar::line(".STRINGZ \"a\\tb\\n\\\"\\\\\\0\"") {
+        Ok(Some(Assm::STRINGZ(ref s))) if s == "a\tb\n\"\\\0" => (),
+        other => panic!("{:?}", other)
+    }
+
+    let assm_lines = vec![".DATA", "GREETING", ".STRINGZ \"Hi\"", "AFTER", ".FILL #7"]
+        .iter().enumerate().map(|(n, text)| located(n + 1, text)).collect();
+    let assm_data = assemble(assm_lines).unwrap();
+    let (_, greeting) = assm_data.labels["GREETING"];
+    let (_, after) = assm_data.labels["AFTER"];
+    assert!(after == greeting + 3);
+    let words: Vec<i16> = (greeting..after + 1).map(|a| encode_word(assm_data.memory[a as usize])).collect();
+    assert!(words == vec![72, 105, 0, 7]);
+}
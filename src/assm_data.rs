@@ -1,6 +1,7 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::HashMap;
 use std::io;
+use std::io::{Read, Write};
 use std::fs::OpenOptions;
 use std::path::Path;
 
@@ -26,6 +27,19 @@ pub struct AssmData<M> {
     pub heap: u16
 }
 
+// Loads another object image on top of this one, keeping existing words
+// where the other image is zero
+pub fn overlay(base: &mut AssmData<i16>, other: &AssmData<i16>) {
+    for addr in 0..other.heap {
+        if other.memory[addr as usize] != 0 {
+            base.memory[addr as usize] = other.memory[addr as usize]
+        }
+    }
+    if other.heap > base.heap {
+        base.heap = other.heap
+    }
+}
+
 pub fn encode_word(mem: Mem) -> i16 {
     match mem {
         Mem::CODE(insn) => encode_insn(insn),
@@ -33,6 +47,46 @@ pub fn encode_word(mem: Mem) -> i16 {
     }
 }
 
+// The symbol table follows the memory image: a label count, then each
+// label's section, absolute address and name.  Object files without one
+// load with no labels.
+fn write_symbols(labels: &HashMap<Label, (Section, u16)>, out: &mut Write) -> Result<(), io::Error> {
+    try!(out.write_u16::<BigEndian>(labels.len() as u16));
+    for (label, &(section, addr)) in labels.iter() {
+        try!(out.write_u8(match section { Section::CODE => 0, Section::DATA => 1 }));
+        try!(out.write_u16::<BigEndian>(addr));
+        try!(out.write_u16::<BigEndian>(label.len() as u16));
+        try!(out.write_all(label.as_bytes()));
+    }
+    Ok(())
+}
+
+fn read_symbols(input: &mut Read) -> Result<HashMap<Label, (Section, u16)>, io::Error> {
+    let mut labels = HashMap::new();
+    let mut rest = Vec::new();
+    try!(input.read_to_end(&mut rest));
+    if rest.is_empty() {
+        return Ok(labels)
+    }
+    let mut symbols = &rest[..];
+    let count = try!(symbols.read_u16::<BigEndian>());
+    for _ in 0..count {
+        let section = match try!(symbols.read_u8()) { 0 => Section::CODE, _ => Section::DATA };
+        let addr = try!(symbols.read_u16::<BigEndian>());
+        let len = try!(symbols.read_u16::<BigEndian>()) as usize;
+        if symbols.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated symbol table"))
+        }
+        let label = match String::from_utf8(symbols[..len].to_vec()) {
+            Ok(label) => label,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad label in symbol table"))
+        };
+        symbols = &symbols[len..];
+        labels.insert(label, (section, addr));
+    }
+    Ok(labels)
+}
+
 pub fn write_object_file(assm_data: AssmData<Mem>, out_file: &str) -> Result<(), io::Error> {
     let mut options = OpenOptions::new();
     options.read(true).write(true).truncate(true);
@@ -41,7 +95,7 @@ pub fn write_object_file(assm_data: AssmData<Mem>, out_file: &str) -> Result<(),
     for addr in 0..assm_data.heap {
         try!(file.write_i16::<BigEndian>(encode_word(assm_data.memory[addr as usize])))
     }
-    Ok(())
+    write_symbols(&assm_data.labels, &mut file)
 }
 
 pub fn read_object_file(in_file: &str) -> Result<AssmData<i16>, io::Error> {
@@ -53,9 +107,10 @@ pub fn read_object_file(in_file: &str) -> Result<AssmData<i16>, io::Error> {
     for addr in 0..heap {
         memory[addr as usize] = try!(file.read_i16::<BigEndian>());
     }
+    let labels = try!(read_symbols(&mut file));
     Ok(AssmData{
         memory: memory,
-        labels: HashMap::new(),
+        labels: labels,
         heap: heap
     })
 }
@@ -5,11 +5,42 @@ extern crate lc4;
 
 use std::convert::AsRef;
 use std::env::args;
+use std::fs::File;
+use std::io::Read;
+use std::process::exit;
 use std::vec::Vec;
 
 use lc4::assembler::*;
 use lc4::assm_data::*;
 
+// Prints diagnostics in the style of rustc, quoting the offending line
+fn print_diagnostics(source_file: &str, diagnostics: &[AssmDiagnostic]) -> () {
+  let mut source = String::new();
+  if let Ok(mut file) = File::open(source_file) {
+    let _ = file.read_to_string(&mut source);
+  }
+  let lines: Vec<&str> = source.lines().collect();
+
+  for d in diagnostics.iter() {
+    let level = match d.severity { Severity::Error => "error", Severity::Warning => "warning" };
+    println!("{}: {}", level, d.problem);
+    println!("  --> {}", d.span);
+    if let Some(text) = lines.get(d.span.line - 1) {
+      let gutter: String = d.span.line.to_string().chars().map(|_| ' ').collect();
+      // Keep tabs so the caret lines up under the quoted text
+      let indent: String = text.chars().take(d.span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+      println!("{} |", gutter);
+      println!("{} | {}", d.span.line, text);
+      println!("{} | {}^", gutter, indent);
+    }
+  }
+
+  let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+  println!("error: could not assemble {} due to {} error{}",
+           source_file, errors, if errors == 1 { "" } else { "s" });
+}
+
 pub fn main() -> () {
 
   let ref source_file: String = match args().nth(1) {
@@ -21,13 +52,23 @@ pub fn main() -> () {
   };
   
   println!("Opening source file {}", source_file);
-  let assm_lines: Vec<Assm> = match read_assembly_file(source_file) {
+  let assm_lines: Vec<Located> = match read_assembly_file(source_file) {
+    Err(AssmError::ParseErrors(diagnostics)) => {
+      print_diagnostics(source_file, &diagnostics);
+      exit(1)
+    },
     Err(err) => panic!("{:?}",err),
     Ok(assms) => assms
   };
 
   println!("Assembling source file {}", source_file);
-  let assm_data: AssmData<Mem> = assemble(assm_lines);
+  let assm_data: AssmData<Mem> = match assemble(assm_lines) {
+    Err(diagnostics) => {
+      print_diagnostics(source_file, &diagnostics);
+      exit(1)
+    },
+    Ok(data) => data
+  };
   
   println!("Debug labels:");
   for (l,addr) in assm_data.labels.iter() {
@@ -1,21 +1,103 @@
+#![feature(box_syntax)]
+
 extern crate lc4;
 
+use std::cell::RefCell;
 use std::cmp::*;
+use std::collections::HashMap;
 use std::env::args;
+use std::fs::File;
 use std::io::*;
+use std::rc::Rc;
 
 use lc4::assm_data::*;
+use lc4::debugger::*;
+use lc4::devices::*;
+use lc4::disassembler::*;
 use lc4::processor::*;
+use lc4::snapshot::*;
+use lc4::video::*;
 
-fn print_proc(cpu: &CPU) -> () {
+fn print_proc(dbg: &Debugger) -> () {
+  let cpu = &dbg.cpu;
   println!("Registers {:?} NZP {} PC {}", cpu.regfile, cpu.nzp, cpu.pc);
+  let by_addr = labels_by_addr(&dbg.labels);
   let radius = 3;
   let low = max(0, cpu.pc as i32 - radius) as usize;
   let high = min(cpu.pc as i32 + radius + 1, cpu.memory.len() as i32) as usize;
-  println!("{} {}", low, high);
   for i in (low .. high) {
-    println!("{} {:#04x} {}", if i == cpu.pc as usize {"*"} else {" "}, i, cpu.memory[i]);
+    let addr = i as u16;
+    let text = match section_at(addr, &dbg.labels) {
+      Section::CODE => disassemble_word(addr, cpu.memory[i], &by_addr),
+      Section::DATA => format!(".FILL x{:04X}", cpu.memory[i] as u16)
+    };
+    let label = by_addr.get(&addr).map(|l| format!("{}: ", l)).unwrap_or(String::new());
+    println!("{} x{:04X} {}{}", if i == cpu.pc as usize {"*"} else {" "}, addr, label, text);
+  }
+}
+
+fn report(dbg: &Debugger, stop: Stop) -> () {
+  match stop {
+    Stop::Stepped => (),
+    Stop::Breakpoint(addr) => match dbg.label_at(addr) {
+      Some(label) => println!("Breakpoint at {:#06x} ({})", addr, label),
+      None => println!("Breakpoint at {:#06x}", addr)
+    },
+    Stop::Watchpoint(hits) => for hit in hits.iter() {
+      match *hit {
+        WatchHit::Load(addr, value) =>
+          println!("Read {:#06x} = {}", addr, value),
+        WatchHit::Store(addr, old, new) =>
+          println!("Write {:#06x}: {} -> {}", addr, old, new),
+        WatchHit::Register(r, old, new) =>
+          println!("R{}: {} -> {}", r, old, new),
+        WatchHit::NZP(old, new) =>
+          println!("NZP: {} -> {}", old, new)
+      }
+    },
+    Stop::Halted => println!("Halted"),
+    Stop::HistoryStart => println!("Reached the start of recorded history"),
+    Stop::Error(err) => println!("Error {:?}", err)
+  }
+  print_proc(&dbg)
+}
+
+// Registers by name, NZP, or a memory location or range
+fn parse_watch(dbg: &Debugger, command: &str, spec: &str) -> Option<Watch> {
+  match spec {
+    "NZP" | "nzp" => return Some(Watch::NZP),
+    _ => ()
+  }
+  if spec.len() == 2 && spec.starts_with("R") {
+    if let Ok(r) = spec[1..].parse::<usize>() {
+      if r < 8 { return Some(Watch::Register(r)) }
+    }
   }
+  dbg.resolve_range(spec).map(|(low, high)| match command {
+    "rwatch" => Watch::Read(low, high),
+    "awatch" => Watch::Access(low, high),
+    _ => Watch::Write(low, high)
+  })
+}
+
+// [hit N] [if CONDITION]
+fn parse_breakpoint(dbg: &Debugger, options: &[&str]) -> std::result::Result<Breakpoint, String> {
+  let mut bp = Breakpoint::new();
+  let mut rest = options;
+  if rest.len() >= 2 && rest[0] == "hit" {
+    bp.hit_count = try!(rest[1].parse().map_err(|_| format!("Bad hit count {}", rest[1])));
+    rest = &rest[2..];
+  }
+  if rest.len() >= 2 && rest[0] == "if" {
+    let text = rest[1..].join(" ");
+    let condition = try!(dbg.parse_condition(&text).map_err(|e| format!("{:?}", e)));
+    bp.condition = Some((text, condition));
+    rest = &[];
+  }
+  if !rest.is_empty() {
+    return Err(format!("Unexpected {}", rest.join(" ")))
+  }
+  Ok(bp)
 }
 
 pub fn main() -> () {
@@ -27,28 +109,136 @@ pub fn main() -> () {
     }
   };
 
-  let assm_data: AssmData<i16> = match read_object_file(source_file) {
-    Err(err) => panic!("{:?}", err),
-    Ok(data) => data
+  // Start from either an object file or a saved snapshot
+  let snapshot = is_snapshot_file(source_file);
+  let assm_data: AssmData<i16> = if snapshot {
+    AssmData{ memory: box [0;0x10000], labels: HashMap::new(), heap: 0 }
+  } else {
+    match read_object_file(source_file) {
+      Err(err) => panic!("{:?}", err),
+      Ok(data) => data
+    }
   };
 
+  let labels = assm_data.labels.clone();
   let mut cpu = boot(assm_data);
+  // Stdin is shared: the program's keyboard takes what is typed while it
+  // runs, and the prompt reads the rest as commands
+  let keyboard = Rc::new(RefCell::new(Keyboard::from_reader(Box::new(stdin()))));
+  cpu.bus.attach(Box::new(keyboard.clone()));
+  cpu.bus.attach(Box::new(AsciiDisplay::new(stdout())));
+  cpu.bus.attach(Box::new(Timer::new(TimerClock::Instructions)));
+  if snapshot {
+    if let Err(err) = load_snapshot(&mut cpu, source_file) {
+      panic!("{:?}", err)
+    }
+  }
 
-  print_proc(&cpu);
+  let mut dbg = Debugger::new(cpu, labels);
 
-  let stdin = stdin();
+  print_proc(&dbg);
 
-  for liner in stdin.lock().lines() {
-    match liner {
-      Err(err) => panic!("{:?}", err),
-      Ok(line) => match line.trim() {
-        "p" => print_proc(&cpu),
-        "s" => match cpu.step() {
-          Err(err) => panic!("{:?}", err),
-          Ok(()) => ()
-        },
-        "q" => break,
-        _ => continue
+  loop {
+    // Release the keyboard before running the program
+    let next = keyboard.borrow_mut().host_line();
+    match next {
+      None => break,
+      Some(line) => {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+          (Some("p"), _) => print_proc(&dbg),
+          (Some("s"), n) | (Some("step"), n) => match n.map(|n| n.parse()) {
+            None => { let stop = dbg.step(1); report(&dbg, stop) },
+            Some(Ok(n)) => { let stop = dbg.step(n); report(&dbg, stop) },
+            Some(Err(_)) => println!("Bad instruction count")
+          },
+          (Some("n"), _) | (Some("next"), _) => {
+            let stop = dbg.next();
+            report(&dbg, stop)
+          },
+          (Some("back"), n) => match n.map(|n| n.parse()) {
+            None => { dbg.back(1); print_proc(&dbg) },
+            Some(Ok(n)) => { dbg.back(n); print_proc(&dbg) },
+            Some(Err(_)) => println!("Bad instruction count")
+          },
+          (Some("rc"), _) | (Some("reverse-continue"), _) => {
+            let stop = dbg.reverse_cont();
+            report(&dbg, stop)
+          },
+          (Some("c"), _) | (Some("continue"), _) => {
+            let stop = dbg.cont();
+            report(&dbg, stop)
+          },
+          (Some("b"), None) | (Some("break"), None) =>
+            for (addr, bp) in dbg.breakpoints().iter() {
+              match bp.condition {
+                Some((ref text, _)) => println!("{:#06x} hit {}/{} if {}", addr, bp.hits, bp.hit_count, text),
+                None => println!("{:#06x} hit {}/{}", addr, bp.hits, bp.hit_count)
+              }
+            },
+          (Some("b"), Some(location)) | (Some("break"), Some(location)) => {
+            let options: Vec<&str> = words.collect();
+            match (dbg.resolve(location), parse_breakpoint(&dbg, &options)) {
+              (Some(addr), Ok(bp)) => {
+                dbg.set_breakpoint(addr, bp);
+                println!("Breakpoint at {:#06x}", addr)
+              },
+              (None, _) => println!("Unknown location {}", location),
+              (_, Err(err)) => println!("{}", err)
+            }
+          },
+          (Some("d"), None) | (Some("delete"), None) => dbg.clear_breakpoints(),
+          (Some("d"), Some(location)) | (Some("delete"), Some(location)) =>
+            match dbg.resolve(location) {
+              Some(addr) if dbg.remove_breakpoint(addr) => (),
+              _ => println!("No breakpoint at {}", location)
+            },
+          (Some(command @ "watch"), Some(spec)) |
+          (Some(command @ "rwatch"), Some(spec)) |
+          (Some(command @ "awatch"), Some(spec)) =>
+            match parse_watch(&dbg, command, spec) {
+              Some(watch) => dbg.add_watch(watch),
+              None => println!("Unknown watch {}", spec)
+            },
+          (Some("unwatch"), None) => dbg.clear_watches(),
+          (Some("unwatch"), Some(spec)) => {
+            let candidates: Vec<Watch> = ["watch", "rwatch", "awatch"].iter()
+              .filter_map(|c| parse_watch(&dbg, c, spec)).collect();
+            let mut removed = false;
+            for &watch in candidates.iter() {
+              removed = dbg.remove_watch(watch) || removed
+            }
+            if !removed { println!("No watch on {}", spec) }
+          },
+          (Some("save"), Some(path)) => match save_snapshot(&dbg.cpu, path) {
+            Err(err) => println!("{:?}", err),
+            Ok(()) => ()
+          },
+          // A failed load leaves the machine untouched, so history stays valid
+          (Some("load"), Some(path)) => match load_snapshot(&mut dbg.cpu, path) {
+            Err(err) => println!("{:?}", err),
+            Ok(()) => { dbg.clear_history(); print_proc(&dbg) }
+          },
+          (Some("trace"), Some("off")) => dbg.set_trace(None),
+          (Some("trace"), Some(path)) => match File::create(path) {
+            Err(err) => println!("{:?}", err),
+            Ok(file) => dbg.set_trace(Some(Box::new(LineWriter::new(file))))
+          },
+          (Some("v"), Some(path)) => match save_frame(&dbg.cpu.memory, path) {
+            Err(err) => println!("{:?}", err),
+            Ok(()) => ()
+          },
+          // input [TEXT] queues TEXT then Enter, so a bare input sends Enter
+          (Some("input"), _) => {
+            let text = line.trim_left()["input".len()..].trim_left();
+            let text = text.trim_right_matches(&['\r', '\n'][..]);
+            let mut keyboard = keyboard.borrow_mut();
+            keyboard.push_str(text);
+            keyboard.push_str("\n")
+          },
+          (Some("q"), _) => break,
+          _ => continue
+        }
       }
     }
     
@@ -0,0 +1,25 @@
+extern crate lc4;
+
+use std::env::args;
+
+use lc4::assm_data::*;
+use lc4::disassembler::*;
+
+pub fn main() -> () {
+  let ref object_file: String = match args().nth(1) {
+    Some(arg) => arg,
+    None => {
+      println!("Usage: lc4-disasm OBJECT");
+      return
+    }
+  };
+
+  let assm_data: AssmData<i16> = match read_object_file(object_file) {
+    Err(err) => panic!("{:?}", err),
+    Ok(data) => data
+  };
+
+  for line in disassemble_image(&assm_data).iter() {
+    println!("{}", line);
+  }
+}
@@ -0,0 +1,135 @@
+#![feature(box_syntax)]
+
+extern crate lc4;
+
+use std::collections::HashMap;
+use std::env::args;
+use std::fs::File;
+use std::io::{stdin, stdout, BufWriter, Write};
+use std::process::exit;
+
+use lc4::architecture::{N, Z, P};
+use lc4::assm_data::*;
+use lc4::debugger::parse_addr;
+use lc4::devices::*;
+use lc4::processor::*;
+use lc4::trace::*;
+
+// Process exit codes
+const EXIT_HALT : i32 = 0;
+const EXIT_ERROR : i32 = 1;
+const EXIT_LIMIT : i32 = 2;
+const EXIT_USAGE : i32 = 3;
+
+fn usage() -> ! {
+  println!("Usage: lc4-run [-n LIMIT] [-m START:END]... [-t TRACE] OBJECT...");
+  exit(EXIT_USAGE)
+}
+
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+  let mut bounds = s.splitn(2, ':');
+  match (bounds.next().and_then(parse_addr), bounds.next().and_then(parse_addr)) {
+    (Some(low), Some(high)) if low <= high => Some((low, high)),
+    _ => None
+  }
+}
+
+fn print_state(cpu: &CPU, ranges: &Vec<(u16, u16)>) {
+  for (r, value) in cpu.regfile.iter().enumerate() {
+    println!("R{} x{:04X}", r, *value as u16);
+  }
+  println!("PC x{:04X}", cpu.pc);
+  println!("NZP {}{}{}",
+           if cpu.nzp & N != 0 {"n"} else {"-"},
+           if cpu.nzp & Z != 0 {"z"} else {"-"},
+           if cpu.nzp & P != 0 {"p"} else {"-"});
+  println!("PRIV {}", if cpu.priv_status {1} else {0});
+  for &(low, high) in ranges.iter() {
+    for addr in (low as usize) .. (high as usize + 1) {
+      println!("x{:04X} x{:04X}", addr, cpu.memory[addr] as u16);
+    }
+  }
+}
+
+pub fn main() -> () {
+  let mut limit: u64 = 1000000;
+  let mut ranges: Vec<(u16, u16)> = Vec::new();
+  let mut object_files: Vec<String> = Vec::new();
+  let mut trace: Option<BufWriter<File>> = None;
+
+  let mut argv = args().skip(1);
+  while let Some(arg) = argv.next() {
+    match &arg[..] {
+      "-n" => match argv.next().and_then(|n| n.parse().ok()) {
+        Some(n) => limit = n,
+        None => usage()
+      },
+      "-m" => match argv.next().as_ref().and_then(|r| parse_range(r)) {
+        Some(range) => ranges.push(range),
+        None => usage()
+      },
+      "-t" => match argv.next().map(|path| File::create(&path)) {
+        Some(Ok(file)) => trace = Some(BufWriter::new(file)),
+        Some(Err(err)) => {
+          println!("Cannot create trace: {:?}", err);
+          exit(EXIT_USAGE)
+        },
+        None => usage()
+      },
+      _ => object_files.push(arg.clone())
+    }
+  }
+
+  if object_files.is_empty() { usage() }
+
+  let mut image = AssmData{ memory: box [0;0x10000], labels: HashMap::new(), heap: 0 };
+  for object_file in object_files.iter() {
+    match read_object_file(object_file) {
+      Err(err) => {
+        println!("Cannot load {}: {:?}", object_file, err);
+        exit(EXIT_USAGE)
+      },
+      Ok(data) => overlay(&mut image, &data)
+    }
+  }
+
+  let mut cpu = boot(image);
+  cpu.bus.attach(Box::new(Keyboard::from_reader(Box::new(stdin()))));
+  cpu.bus.attach(Box::new(AsciiDisplay::new(stdout())));
+  cpu.bus.attach(Box::new(Timer::new(TimerClock::Instructions)));
+
+  let mut executed: u64 = 0;
+  let code;
+  loop {
+    if cpu.halted() {
+      println!("Halted after {} instructions", executed);
+      code = EXIT_HALT;
+      break
+    }
+    if executed == limit {
+      println!("Instruction limit {} reached", limit);
+      code = EXIT_LIMIT;
+      break
+    }
+    let result = match trace {
+      Some(ref mut out) => trace_step(&mut cpu).map(|line| {
+        let _ = writeln!(out, "{}", line);
+      }),
+      None => cpu.step()
+    };
+    match result {
+      Err(err) => {
+        println!("Error {:?} at PC x{:04X}", err, cpu.pc);
+        code = EXIT_ERROR;
+        break
+      },
+      Ok(()) => executed += 1
+    }
+  }
+
+  if let Some(ref mut out) = trace {
+    let _ = out.flush();
+  }
+  print_state(&cpu, &ranges);
+  exit(code)
+}
@@ -0,0 +1,83 @@
+extern crate lc4;
+
+use std::collections::HashMap;
+use std::env::args;
+use std::process::exit;
+
+use lc4::assm_data::*;
+use lc4::disassembler::*;
+use lc4::trace::*;
+
+// Process exit codes
+const EXIT_SAME : i32 = 0;
+const EXIT_DIVERGED : i32 = 1;
+const EXIT_USAGE : i32 = 2;
+
+fn load(filename: &str) -> Vec<TraceLine> {
+  match read_trace_file(filename) {
+    Ok(lines) => lines,
+    Err(TraceError::ParseError(line)) => {
+      println!("{}:{}: malformed trace line", filename, line);
+      exit(EXIT_USAGE)
+    },
+    Err(TraceError::IoError(err)) => {
+      println!("Cannot read {}: {:?}", filename, err);
+      exit(EXIT_USAGE)
+    }
+  }
+}
+
+fn print_side(name: &str, line: Option<TraceLine>, state: &TraceState, labels: &HashMap<u16, Label>) {
+  match line {
+    Some(line) => {
+      println!("{}: {}", name, line);
+      println!("  x{:04X}: {}", line.pc, disassemble_word(line.pc, line.insn as i16, labels));
+    },
+    None => println!("{}: trace ended", name)
+  }
+  let regs: Vec<String> = state.regfile.iter().enumerate().map(|(r, value)| match *value {
+    Some(value) => format!("R{}=x{:04X}", r, value as u16),
+    None => format!("R{}=?", r)
+  }).collect();
+  let nzp = match state.nzp {
+    Some(nzp) => format!("{}", nzp),
+    None => "?".to_string()
+  };
+  println!("  before: {} NZP={}", regs.join(" "), nzp);
+}
+
+pub fn main() -> () {
+  let argv: Vec<String> = args().collect();
+  if argv.len() != 3 && argv.len() != 4 {
+    println!("Usage: lc4-tracediff OURS THEIRS [OBJECT]");
+    exit(EXIT_USAGE)
+  }
+
+  // Labels from the object file, if given, name jump targets
+  let labels = match argv.get(3) {
+    None => HashMap::new(),
+    Some(object) => match read_object_file(object) {
+      Ok(data) => labels_by_addr(&data.labels),
+      Err(err) => {
+        println!("Cannot read {}: {:?}", object, err);
+        exit(EXIT_USAGE)
+      }
+    }
+  };
+
+  let ours = load(&argv[1]);
+  let theirs = load(&argv[2]);
+
+  match diff_traces(&ours, &theirs) {
+    None => {
+      println!("Traces agree for {} instructions", ours.len());
+      exit(EXIT_SAME)
+    },
+    Some(divergence) => {
+      println!("Traces diverge at instruction {}", divergence.index + 1);
+      print_side(&argv[1], divergence.ours, &divergence.our_state, &labels);
+      print_side(&argv[2], divergence.theirs, &divergence.their_state, &labels);
+      exit(EXIT_DIVERGED)
+    }
+  }
+}
@@ -0,0 +1,138 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cell::RefCell;
+use std::io;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use assm_data::*;
+
+// A memory-mapped device answering loads and stores for the addresses it
+// claims.  Addresses no device claims fall through to RAM.  Devices are
+// also ticked once per executed instruction, and may save and restore
+// their state for machine snapshots.
+pub trait Device {
+  fn claims(&self, addr: u16) -> bool;
+  fn load(&mut self, addr: u16) -> i16;
+  fn store(&mut self, addr: u16, value: i16);
+  fn tick(&mut self, _memory: &Memory<i16>) {}
+  fn save(&self, _out: &mut Write) -> Result<(), io::Error> { Ok(()) }
+  fn restore(&mut self, _input: &mut Read) -> Result<(), io::Error> { Ok(()) }
+}
+
+// Lets a caller keep a handle on a device after attaching it, e.g. to feed
+// input or inspect output while the CPU runs.
+impl<D: Device> Device for Rc<RefCell<D>> {
+  fn claims(&self, addr: u16) -> bool { self.borrow().claims(addr) }
+  fn load(&mut self, addr: u16) -> i16 { self.borrow_mut().load(addr) }
+  fn store(&mut self, addr: u16, value: i16) { self.borrow_mut().store(addr, value) }
+  fn tick(&mut self, memory: &Memory<i16>) { self.borrow_mut().tick(memory) }
+  fn save(&self, out: &mut Write) -> Result<(), io::Error> { self.borrow().save(out) }
+  fn restore(&mut self, input: &mut Read) -> Result<(), io::Error> { self.borrow_mut().restore(input) }
+}
+
+// A data memory access made through the bus: the loaded value, or the
+// previous and stored values
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access { Load(u16, i16), Store(u16, i16, i16) }
+
+pub struct Bus {
+  devices: Vec<Box<Device>>,
+  accesses: Vec<Access>
+}
+
+impl Bus {
+
+  pub fn new() -> Bus {
+    Bus{ devices: Vec::new(), accesses: Vec::new() }
+  }
+
+  // Devices attached earlier take precedence where claims overlap.
+  pub fn attach(&mut self, device: Box<Device>) {
+    self.devices.push(device)
+  }
+
+  fn device_for(&mut self, addr: u16) -> Option<&mut Box<Device>> {
+    self.devices.iter_mut().find(|d| d.claims(addr))
+  }
+
+  pub fn load(&mut self, memory: &Memory<i16>, addr: u16) -> i16 {
+    let value = match self.device_for(addr) {
+      Some(device) => device.load(addr),
+      None => memory[addr as usize]
+    };
+    self.accesses.push(Access::Load(addr, value));
+    value
+  }
+
+  pub fn store(&mut self, memory: &mut Memory<i16>, addr: u16, value: i16) {
+    self.accesses.push(Access::Store(addr, memory[addr as usize], value));
+    match self.device_for(addr) {
+      Some(device) => device.store(addr, value),
+      None => memory[addr as usize] = value
+    }
+  }
+
+  // Accesses made since the last call to clear_accesses
+  pub fn accesses(&self) -> &[Access] {
+    &self.accesses
+  }
+
+  pub fn clear_accesses(&mut self) {
+    self.accesses.clear()
+  }
+
+  // Each device's state is length-prefixed, in attachment order
+  pub fn save(&self, out: &mut Write) -> Result<(), io::Error> {
+    try!(out.write_u16::<BigEndian>(self.devices.len() as u16));
+    for device in self.devices.iter() {
+      let mut state = Vec::new();
+      try!(device.save(&mut state));
+      try!(out.write_u32::<BigEndian>(state.len() as u32));
+      try!(out.write_all(&state));
+    }
+    Ok(())
+  }
+
+  // The bus must have the same devices attached as when it was saved.  All
+  // state is read before any device changes, and if a device rejects its
+  // state every device is put back as it was.
+  pub fn restore(&mut self, input: &mut Read) -> Result<(), io::Error> {
+    let count = try!(input.read_u16::<BigEndian>()) as usize;
+    if count != self.devices.len() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "device count mismatch"))
+    }
+    let mut states = Vec::new();
+    for _ in 0..count {
+      let len = try!(input.read_u32::<BigEndian>()) as u64;
+      let mut state = Vec::new();
+      try!(input.take(len).read_to_end(&mut state));
+      if state.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated device state"))
+      }
+      states.push(state);
+    }
+
+    let mut backups = Vec::new();
+    for device in self.devices.iter() {
+      let mut backup = Vec::new();
+      try!(device.save(&mut backup));
+      backups.push(backup);
+    }
+    for (i, state) in states.iter().enumerate() {
+      if let Err(err) = self.devices[i].restore(&mut &state[..]) {
+        for (device, backup) in self.devices.iter_mut().zip(backups.iter()) {
+          let _ = device.restore(&mut &backup[..]);
+        }
+        return Err(err)
+      }
+    }
+    Ok(())
+  }
+
+  pub fn tick(&mut self, memory: &Memory<i16>) {
+    for device in self.devices.iter_mut() {
+      device.tick(memory)
+    }
+  }
+
+}
@@ -1,9 +1,20 @@
 use architecture::*;
 
+/// Why an instruction word could not be decoded.
 #[derive(Debug, Eq, PartialEq)]
-pub enum DecodeError { BadOpcode }
+pub enum DecodeError {
+  /// The top four bits are not an LC4 opcode (`0011`, `1011` or `1110`).
+  BadOpcode
+}
 
+/// Decodes LC4 instruction words.
+///
+/// Decoding inverts `encoder::encode_insn`: for any instruction whose
+/// fields are in range, encoding and then decoding gives back the same
+/// instruction.  The other way round, re-encoding a decoded word gives back
+/// the word up to its don't-care bits.
 pub trait Controller {
+  /// Decodes one instruction word.
   fn decode(self) -> Result<Insn, DecodeError>;
 }
   
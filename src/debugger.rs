@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Write;
+
+use architecture::*;
+use assm_data::*;
+use bus::*;
+use controller::*;
+use processor::*;
+use trace::*;
+
+// Memory watches cover an inclusive address range
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Watch { Read(u16, u16), Write(u16, u16), Access(u16, u16), Register(RName), NZP }
+
+// What tripped a watch, with the old and new values where they differ
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchHit { Load(u16, i16), Store(u16, i16, i16), Register(RName, i16, i16), NZP(CC, CC) }
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BinOp { Add, Sub, Eq, Ne, Lt, Le, Gt, Ge, And, Or }
+
+// Breakpoint conditions.  Values are signed; comparisons and logic
+// operators yield 1 or 0.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expr {
+  Const(i32),
+  Reg(RName),
+  PC,
+  NZP,
+  Flag(CC),
+  Priv,
+  Label(Label),
+  Mem(Box<Expr>),
+  Not(Box<Expr>),
+  Neg(Box<Expr>),
+  Binary(BinOp, Box<Expr>, Box<Expr>)
+}
+
+pub fn ident(name: &str) -> Expr {
+  match name {
+    "PC" => Expr::PC,
+    "NZP" => Expr::NZP,
+    "N" => Expr::Flag(N),
+    "Z" => Expr::Flag(Z),
+    "P" => Expr::Flag(P),
+    "PRIV" => Expr::Priv,
+    _ => match (name.len(), name.starts_with("R"), name[1..].parse::<usize>()) {
+      (2, true, Ok(r)) if r < 8 => Expr::Reg(r),
+      _ => Expr::Label(name.to_string())
+    }
+  }
+}
+
+pub fn fold_left(first: Expr, rest: Vec<(BinOp, Expr)>) -> Expr {
+  rest.into_iter().fold(first, |l, (op, r)| Expr::Binary(op, box l, box r))
+}
+
+peg_file! expr_grammar("grammar/expr.pegjs");
+
+#[derive(Debug)]
+pub enum ConditionError { ParseError(expr_grammar::ParseError), UnknownLabel(Label) }
+
+impl From<expr_grammar::ParseError> for ConditionError {
+  fn from(err: expr_grammar::ParseError) -> ConditionError {
+    ConditionError::ParseError(err)
+  }
+}
+
+// Memory is read directly, so conditions never disturb devices
+pub fn eval(cpu: &CPU, expr: &Expr) -> i32 {
+  match *expr {
+    Expr::Const(n) => n,
+    Expr::Reg(r) => cpu.regfile[r] as i32,
+    Expr::PC => cpu.pc as i32,
+    Expr::NZP => cpu.nzp as i32,
+    Expr::Flag(flag) => (cpu.nzp & flag != 0) as i32,
+    Expr::Priv => cpu.priv_status as i32,
+    Expr::Label(_) => 0,
+    Expr::Mem(ref addr) => cpu.memory[eval(cpu, addr) as u16 as usize] as i32,
+    Expr::Not(ref e) => (eval(cpu, e) == 0) as i32,
+    Expr::Neg(ref e) => eval(cpu, e).wrapping_neg(),
+    Expr::Binary(op, ref l, ref r) => {
+      let (l, r) = (eval(cpu, l), eval(cpu, r));
+      match op {
+        BinOp::Add => l.wrapping_add(r),
+        BinOp::Sub => l.wrapping_sub(r),
+        BinOp::Eq => (l == r) as i32,
+        BinOp::Ne => (l != r) as i32,
+        BinOp::Lt => (l < r) as i32,
+        BinOp::Le => (l <= r) as i32,
+        BinOp::Gt => (l > r) as i32,
+        BinOp::Ge => (l >= r) as i32,
+        BinOp::And => (l != 0 && r != 0) as i32,
+        BinOp::Or => (l != 0 || r != 0) as i32
+      }
+    }
+  }
+}
+
+// Stops once the condition (if any) has held on `hit_count` arrivals.  The
+// condition keeps its source text for listing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Breakpoint {
+  pub condition: Option<(String, Expr)>,
+  pub hit_count: u64,
+  pub hits: u64
+}
+
+impl Breakpoint {
+  pub fn new() -> Breakpoint {
+    Breakpoint{ condition: None, hit_count: 1, hits: 0 }
+  }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Stop {
+  Stepped,
+  Breakpoint(u16),
+  Watchpoint(Vec<WatchHit>),
+  Halted,
+  Error(CPUError),
+  HistoryStart
+}
+
+// What one step overwrote: enough to undo it.  An instruction writes at
+// most one register and one memory word.  Device state is not recorded,
+// so stepping back over I/O does not un-read or un-print.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Delta {
+  pub pc: u16,
+  pub nzp: CC,
+  pub priv_status: bool,
+  pub register: Option<(RName, i16)>,
+  pub store: Option<(u16, i16)>
+}
+
+// Oldest deltas are dropped beyond this many steps
+pub const HISTORY_LIMIT : usize = 1000000;
+
+pub struct Debugger {
+  pub cpu: CPU,
+  pub labels: HashMap<Label, (Section, u16)>,
+  breakpoints: BTreeMap<u16, Breakpoint>,
+  watches: Vec<Watch>,
+  history: VecDeque<Delta>,
+  trace: Option<Box<Write>>
+}
+
+// Accepts xHEX, 0xHEX or decimal addresses
+pub fn parse_addr(s: &str) -> Option<u16> {
+  if s.starts_with("0x") {
+    u16::from_str_radix(&s[2..], 16).ok()
+  } else if s.starts_with("x") {
+    u16::from_str_radix(&s[1..], 16).ok()
+  } else {
+    s.parse().ok()
+  }
+}
+
+impl Debugger {
+
+  pub fn new(cpu: CPU, labels: HashMap<Label, (Section, u16)>) -> Debugger {
+    Debugger{ cpu: cpu, labels: labels, breakpoints: BTreeMap::new(),
+              watches: Vec::new(), history: VecDeque::new(), trace: None }
+  }
+
+  // Labels take precedence over addresses
+  pub fn resolve(&self, location: &str) -> Option<u16> {
+    match self.labels.get(location) {
+      Some(&(_, addr)) => Some(addr),
+      None => parse_addr(location)
+    }
+  }
+
+  pub fn label_at(&self, addr: u16) -> Option<&Label> {
+    self.labels.iter().find(|&(_, &(_, a))| a == addr).map(|(l, _)| l)
+  }
+
+  // A location or a LOW:HIGH pair of locations
+  pub fn resolve_range(&self, range: &str) -> Option<(u16, u16)> {
+    let mut bounds = range.splitn(2, ':');
+    let low = bounds.next().and_then(|l| self.resolve(l));
+    match (low, bounds.next().map(|h| self.resolve(h))) {
+      (Some(low), None) => Some((low, low)),
+      (Some(low), Some(Some(high))) if low <= high => Some((low, high)),
+      _ => None
+    }
+  }
+
+  // Parses a condition and binds its labels to their addresses
+  pub fn parse_condition(&self, condition: &str) -> Result<Expr, ConditionError> {
+    let expr = try!(expr_grammar::condition(condition));
+    self.bind(expr)
+  }
+
+  fn bind(&self, expr: Expr) -> Result<Expr, ConditionError> {
+    Ok(match expr {
+      Expr::Label(label) => match self.labels.get(&label) {
+        Some(&(_, addr)) => Expr::Const(addr as i32),
+        None => return Err(ConditionError::UnknownLabel(label))
+      },
+      Expr::Mem(e) => Expr::Mem(box try!(self.bind(*e))),
+      Expr::Not(e) => Expr::Not(box try!(self.bind(*e))),
+      Expr::Neg(e) => Expr::Neg(box try!(self.bind(*e))),
+      Expr::Binary(op, l, r) =>
+        Expr::Binary(op, box try!(self.bind(*l)), box try!(self.bind(*r))),
+      e => e
+    })
+  }
+
+  pub fn breakpoints(&self) -> &BTreeMap<u16, Breakpoint> {
+    &self.breakpoints
+  }
+
+  pub fn add_breakpoint(&mut self, addr: u16) {
+    self.set_breakpoint(addr, Breakpoint::new())
+  }
+
+  pub fn set_breakpoint(&mut self, addr: u16, breakpoint: Breakpoint) {
+    self.breakpoints.insert(addr, breakpoint);
+  }
+
+  pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+    self.breakpoints.remove(&addr).is_some()
+  }
+
+  pub fn clear_breakpoints(&mut self) {
+    self.breakpoints.clear()
+  }
+
+  pub fn watches(&self) -> &Vec<Watch> {
+    &self.watches
+  }
+
+  pub fn add_watch(&mut self, watch: Watch) {
+    if !self.watches.contains(&watch) {
+      self.watches.push(watch)
+    }
+  }
+
+  pub fn remove_watch(&mut self, watch: Watch) -> bool {
+    let before = self.watches.len();
+    self.watches.retain(|&w| w != watch);
+    self.watches.len() < before
+  }
+
+  pub fn clear_watches(&mut self) {
+    self.watches.clear()
+  }
+
+  fn watch_hits(&self, regfile: [i16; 8], nzp: CC) -> Vec<WatchHit> {
+    let mut hits = Vec::new();
+    for &watch in self.watches.iter() {
+      match watch {
+        Watch::Register(r) => if self.cpu.regfile[r] != regfile[r] {
+          hits.push(WatchHit::Register(r, regfile[r], self.cpu.regfile[r]))
+        },
+        Watch::NZP => if self.cpu.nzp != nzp {
+          hits.push(WatchHit::NZP(nzp, self.cpu.nzp))
+        },
+        Watch::Read(low, high) | Watch::Write(low, high) | Watch::Access(low, high) =>
+          for &access in self.cpu.bus.accesses().iter() {
+            match (watch, access) {
+              (Watch::Read(_, _), Access::Load(addr, value)) |
+              (Watch::Access(_, _), Access::Load(addr, value)) =>
+                if low <= addr && addr <= high {
+                  hits.push(WatchHit::Load(addr, value))
+                },
+              (Watch::Write(_, _), Access::Store(addr, old, new)) |
+              (Watch::Access(_, _), Access::Store(addr, old, new)) =>
+                if low <= addr && addr <= high {
+                  hits.push(WatchHit::Store(addr, old, new))
+                },
+              _ => ()
+            }
+          }
+      }
+    }
+    hits
+  }
+
+  fn step_once(&mut self) -> Option<Stop> {
+    if self.cpu.halted() {
+      return Some(Stop::Halted)
+    }
+    let (pc, regfile, nzp, priv_status) =
+      (self.cpu.pc, self.cpu.regfile, self.cpu.nzp, self.cpu.priv_status);
+    let insn = self.cpu.memory[pc as usize] as u16;
+    match self.cpu.step() {
+      Err(err) => Some(Stop::Error(err)),
+      Ok(()) => {
+        if let Some(ref mut out) = self.trace {
+          let _ = writeln!(out, "{}", TraceLine::retired(pc, insn, &self.cpu));
+        }
+        self.record(Delta{
+          pc: pc,
+          nzp: nzp,
+          priv_status: priv_status,
+          register: (0..8).find(|&r| self.cpu.regfile[r] != regfile[r])
+                          .map(|r| (r, regfile[r])),
+          store: self.cpu.bus.accesses().iter().filter_map(|&access| match access {
+            Access::Store(addr, old, _) => Some((addr, old)),
+            Access::Load(_, _) => None
+          }).next()
+        });
+        let hits = self.watch_hits(regfile, nzp);
+        if hits.is_empty() { None } else { Some(Stop::Watchpoint(hits)) }
+      }
+    }
+  }
+
+  fn record(&mut self, delta: Delta) {
+    if self.history.len() == HISTORY_LIMIT {
+      self.history.pop_front();
+    }
+    self.history.push_back(delta)
+  }
+
+  // Writes a trace line for every instruction executed while set
+  pub fn set_trace(&mut self, trace: Option<Box<Write>>) {
+    self.trace = trace
+  }
+
+  // Needed whenever the machine state is replaced wholesale
+  pub fn clear_history(&mut self) {
+    self.history.clear()
+  }
+
+  pub fn history_len(&self) -> usize {
+    self.history.len()
+  }
+
+  fn undo(&mut self) -> bool {
+    match self.history.pop_back() {
+      None => false,
+      Some(delta) => {
+        self.cpu.pc = delta.pc;
+        self.cpu.nzp = delta.nzp;
+        self.cpu.priv_status = delta.priv_status;
+        if let Some((r, value)) = delta.register {
+          self.cpu.regfile[r] = value
+        }
+        if let Some((addr, value)) = delta.store {
+          self.cpu.memory[addr as usize] = value
+        }
+        true
+      }
+    }
+  }
+
+  // Undoes up to n steps, returning how many were undone
+  pub fn back(&mut self, n: u64) -> u64 {
+    let mut undone = 0;
+    while undone < n && self.undo() {
+      undone += 1
+    }
+    undone
+  }
+
+  // Undoes steps until the PC reaches a breakpoint whose condition holds.
+  // Hit counts are not consulted or updated when running backwards.
+  pub fn reverse_cont(&mut self) -> Stop {
+    while self.undo() {
+      let pc = self.cpu.pc;
+      let holds = match self.breakpoints.get(&pc) {
+        None => false,
+        Some(bp) => bp.condition.as_ref().map_or(true, |&(_, ref c)| eval(&self.cpu, c) != 0)
+      };
+      if holds { return Stop::Breakpoint(pc) }
+    }
+    Stop::HistoryStart
+  }
+
+  fn at_breakpoint(&mut self) -> Option<Stop> {
+    let pc = self.cpu.pc;
+    let holds = match self.breakpoints.get(&pc) {
+      None => return None,
+      Some(bp) => bp.condition.as_ref().map_or(true, |&(_, ref c)| eval(&self.cpu, c) != 0)
+    };
+    match self.breakpoints.get_mut(&pc) {
+      Some(ref mut bp) if holds => {
+        bp.hits += 1;
+        if bp.hits >= bp.hit_count { Some(Stop::Breakpoint(pc)) } else { None }
+      },
+      _ => None
+    }
+  }
+
+  // Executes up to n instructions, stopping early at a breakpoint
+  pub fn step(&mut self, n: u64) -> Stop {
+    for _ in 0..n {
+      if let Some(stop) = self.step_once() { return stop }
+      if let Some(stop) = self.at_breakpoint() { return stop }
+    }
+    Stop::Stepped
+  }
+
+  // Runs until a breakpoint other than the one at the current PC
+  pub fn cont(&mut self) -> Stop {
+    loop {
+      if let Some(stop) = self.step_once() { return stop }
+      if let Some(stop) = self.at_breakpoint() { return stop }
+    }
+  }
+
+  // Steps one instruction, running a JSR, JSRR or TRAP through to its return
+  pub fn next(&mut self) -> Stop {
+    let call = match (self.cpu.memory[self.cpu.pc as usize] as u16).decode() {
+      Ok(InsnGen::JSR(_)) | Ok(InsnGen::JSRr(_)) => true,
+      Ok(InsnGen::TRAP(_)) => !self.cpu.halted(),
+      _ => false
+    };
+    if !call {
+      return self.step(1)
+    }
+    let ret = self.cpu.pc.wrapping_add(1);
+    loop {
+      if let Some(stop) = self.step_once() { return stop }
+      if self.cpu.pc == ret { return Stop::Stepped }
+      if let Some(stop) = self.at_breakpoint() { return stop }
+    }
+  }
+
+}
+
+#[test]
+fn debugger_unit_tests () {
+  // CONST R1, 3; ADD R1, R1, -1; BRp -2; JSR 1; TRAP x25; ...; RET at x10
+  let mut cpu = boot_program(&[0x9203, 0x127F, 0x03FE, 0x4801, 0xF025]);
+  cpu.memory[0x10] = 0xC1C0u16 as i16;
+  let mut labels = HashMap::new();
+  labels.insert("LOOP".to_string(), (Section::CODE, 1));
+
+  let mut dbg = Debugger::new(cpu, labels);
+  assert!(dbg.resolve("LOOP") == Some(1));
+  assert!(dbg.resolve("x10") == Some(0x10));
+  assert!(dbg.resolve("MISSING") == None);
+
+  dbg.add_breakpoint(2);
+  assert!(dbg.cont() == Stop::Breakpoint(2));
+  assert!(dbg.cpu.regfile[R1] == 2);
+  assert!(dbg.cont() == Stop::Breakpoint(2));
+  assert!(dbg.step(5) == Stop::Breakpoint(2));
+  assert!(dbg.cpu.regfile[R1] == 0);
+
+  dbg.clear_breakpoints();
+  assert!(dbg.step(1) == Stop::Stepped);
+  assert!(dbg.cpu.pc == 3);
+  assert!(dbg.next() == Stop::Stepped);
+  assert!(dbg.cpu.pc == 4);
+  assert!(dbg.cont() == Stop::Halted);
+}
+
+#[test]
+fn watch_unit_tests () {
+  // CONST R6, 0; HICONST R6, x20; STR R1, R6, 1; LDR R2, R6, 1; ADD R1, R1, 1
+  let mut dbg = Debugger::new(boot_program(&[0x9C00, 0xDD20, 0x7381, 0x6581, 0x1261]), HashMap::new());
+  dbg.cpu.memory[0x2001] = 7;
+  dbg.cpu.regfile[R1] = 3;
+
+  assert!(dbg.resolve_range("x2000:x2003") == Some((0x2000, 0x2003)));
+  assert!(dbg.resolve_range("x2003:x2000") == None);
+  dbg.add_watch(Watch::Write(0x2000, 0x2003));
+  dbg.add_watch(Watch::Register(R1));
+  assert!(dbg.cont() == Stop::Watchpoint(vec![WatchHit::Store(0x2001, 7, 3)]));
+  assert!(dbg.cont() == Stop::Watchpoint(vec![WatchHit::Register(R1, 3, 4)]));
+
+  dbg.cpu.pc = 3;
+  dbg.clear_watches();
+  dbg.add_watch(Watch::Read(0x2001, 0x2001));
+  dbg.add_watch(Watch::NZP);
+  assert!(dbg.step(1) == Stop::Watchpoint(vec![WatchHit::Load(0x2001, 3)]));
+  assert!(dbg.step(1) == Stop::Stepped);
+  assert!(dbg.remove_watch(Watch::NZP));
+  assert!(!dbg.remove_watch(Watch::NZP));
+}
+
+#[test]
+fn condition_unit_tests () {
+  let mut labels = HashMap::new();
+  labels.insert("LOOP".to_string(), (Section::CODE, 1));
+  labels.insert("COUNT".to_string(), (Section::DATA, 0x4000));
+  let mut dbg = Debugger::new(boot_program(&[]), labels);
+  dbg.cpu.regfile[R1] = -2;
+  dbg.cpu.memory[0x4000] = 4;
+  dbg.cpu.nzp = N;
+
+  let holds = |dbg: &Debugger, c: &str| eval(&dbg.cpu, &dbg.parse_condition(c).unwrap()) != 0;
+  assert!(holds(&dbg, "R1 == -2 && mem[x4000] > 3"));
+  assert!(holds(&dbg, "mem[COUNT] - 1 - 1 == 2"));
+  assert!(holds(&dbg, "N && !Z || R1 == 0"));
+  assert!(holds(&dbg, "(R1 + 2 == 0) && NZP == 4 && LOOP == 1"));
+  assert!(!holds(&dbg, "R1 != -2 || PRIV"));
+  assert!(dbg.parse_condition("R1 ==").is_err());
+  match dbg.parse_condition("mem[MISSING] == 0") {
+    Err(ConditionError::UnknownLabel(l)) => assert!(l == "MISSING"),
+    _ => panic!("expected an unknown label")
+  }
+}
+
+#[test]
+fn conditional_breakpoint_unit_tests () {
+  // CONST R1, 3; ADD R1, R1, -1; BRp -2; TRAP x25
+  let mut dbg = Debugger::new(boot_program(&[0x9203, 0x127F, 0x03FE, 0xF025]), HashMap::new());
+
+  dbg.set_breakpoint(2, Breakpoint{
+    condition: Some(("R1 < 3".to_string(), Expr::Binary(BinOp::Lt, box Expr::Reg(R1), box Expr::Const(3)))),
+    hit_count: 2,
+    hits: 0
+  });
+  assert!(dbg.cont() == Stop::Breakpoint(2));
+  assert!(dbg.cpu.regfile[R1] == 1);
+  assert!(dbg.breakpoints()[&2].hits == 2);
+  assert!(dbg.cont() == Stop::Breakpoint(2));
+  assert!(dbg.cont() == Stop::Halted);
+}
+
+#[test]
+fn reverse_unit_tests () {
+  // CONST R6, 0; HICONST R6, x20; CONST R1, 3; STR R1, R6, 0; ADD R1, R1, -1; BRp -3; TRAP x25
+  let mut dbg = Debugger::new(boot_program(&[0x9C00, 0xDD20, 0x9203, 0x7380, 0x127F, 0x03FD, 0xF025]), HashMap::new());
+
+  assert!(dbg.cont() == Stop::Halted);
+  assert!(dbg.cpu.memory[0x2000] == 1 && dbg.cpu.regfile[R1] == 0);
+  assert!(dbg.history_len() == 12);
+
+  assert!(dbg.back(2) == 2);
+  assert!(dbg.cpu.pc == 4 && dbg.cpu.regfile[R1] == 1 && dbg.cpu.nzp == P);
+
+  dbg.add_breakpoint(3);
+  assert!(dbg.reverse_cont() == Stop::Breakpoint(3));
+  assert!(dbg.cpu.memory[0x2000] == 2 && dbg.cpu.regfile[R1] == 1);
+  assert!(dbg.reverse_cont() == Stop::Breakpoint(3));
+  assert!(dbg.cpu.memory[0x2000] == 3);
+
+  assert!(dbg.back(100) == 6);
+  assert!(dbg.cpu.pc == 0 && dbg.cpu.regfile[R6] == 0 && dbg.cpu.memory[0x2000] == 0);
+  assert!(dbg.reverse_cont() == Stop::HistoryStart);
+}
@@ -0,0 +1,326 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assm_data::*;
+use bus::*;
+
+// Status registers report readiness in bit 15
+pub const READY : i16 = -0x8000;
+
+pub const KBSR : u16 = 0xFE00;
+pub const KBDR : u16 = 0xFE02;
+pub const ADSR : u16 = 0xFE04;
+pub const ADDR : u16 = 0xFE06;
+pub const TSR : u16 = 0xFE08;
+pub const TIR : u16 = 0xFE0A;
+
+// Keyboard status/data registers.  Characters come from a scripted queue,
+// topped up from an optional host reader.  The reader runs on its own
+// thread, started on first access, so a program polling the status
+// register never waits on the host.
+pub struct Keyboard {
+  queue: VecDeque<u8>,
+  source: Option<Box<Read + Send>>,
+  input: Option<Receiver<u8>>
+}
+
+impl Keyboard {
+
+  pub fn new() -> Keyboard {
+    Keyboard{ queue: VecDeque::new(), source: None, input: None }
+  }
+
+  pub fn from_reader(source: Box<Read + Send>) -> Keyboard {
+    Keyboard{ queue: VecDeque::new(), source: Some(source), input: None }
+  }
+
+  pub fn push_str(&mut self, input: &str) {
+    self.queue.extend(input.bytes())
+  }
+
+  fn start_reader(&mut self) {
+    if let Some(mut source) = self.source.take() {
+      let (sender, receiver) = channel();
+      thread::spawn(move || {
+        let mut byte = [0u8; 1];
+        while let Ok(1) = source.read(&mut byte) {
+          if sender.send(byte[0]).is_err() { break }
+        }
+      });
+      self.input = Some(receiver)
+    }
+  }
+
+  // Takes whatever the reader has delivered so far, without waiting
+  fn fill(&mut self) {
+    self.start_reader();
+    let mut disconnected = false;
+    if let Some(ref input) = self.input {
+      loop {
+        match input.try_recv() {
+          Ok(byte) => self.queue.push_back(byte),
+          Err(TryRecvError::Empty) => break,
+          Err(TryRecvError::Disconnected) => { disconnected = true; break }
+        }
+      }
+    }
+    if disconnected { self.input = None }
+  }
+
+  // Waits for a line of host input the program has not taken, for a host
+  // prompt sharing the reader with the program.  None once input ends.
+  pub fn host_line(&mut self) -> Option<String> {
+    self.start_reader();
+    let mut line = Vec::new();
+    if let Some(ref input) = self.input {
+      while let Ok(byte) = input.recv() {
+        line.push(byte);
+        if byte == b'\n' { break }
+      }
+    }
+    if line.is_empty() { None } else { Some(String::from_utf8_lossy(&line).into_owned()) }
+  }
+
+}
+
+impl Device for Keyboard {
+
+  fn claims(&self, addr: u16) -> bool { addr == KBSR || addr == KBDR }
+
+  fn load(&mut self, addr: u16) -> i16 {
+    self.fill();
+    match addr {
+      KBSR => if self.queue.is_empty() { 0 } else { READY },
+      KBDR => self.queue.pop_front().map(|c| c as i16).unwrap_or(0),
+      _ => 0
+    }
+  }
+
+  fn store(&mut self, _addr: u16, _value: i16) {}
+
+  // Only pending input is saved; the host reader stays as attached
+  fn save(&self, out: &mut Write) -> Result<(), io::Error> {
+    try!(out.write_u32::<BigEndian>(self.queue.len() as u32));
+    for &c in self.queue.iter() {
+      try!(out.write_u8(c));
+    }
+    Ok(())
+  }
+
+  fn restore(&mut self, input: &mut Read) -> Result<(), io::Error> {
+    self.queue.clear();
+    let len = try!(input.read_u32::<BigEndian>());
+    for _ in 0..len {
+      self.queue.push_back(try!(input.read_u8()));
+    }
+    Ok(())
+  }
+
+}
+
+// ASCII display status/data registers.  The display is always ready, and
+// each character written to the data register goes to the sink.
+pub struct AsciiDisplay<W> {
+  sink: W
+}
+
+impl<W: Write> AsciiDisplay<W> {
+
+  pub fn new(sink: W) -> AsciiDisplay<W> {
+    AsciiDisplay{ sink: sink }
+  }
+
+  pub fn sink(&self) -> &W {
+    &self.sink
+  }
+
+}
+
+impl<W: Write> Device for AsciiDisplay<W> {
+
+  fn claims(&self, addr: u16) -> bool { addr == ADSR || addr == ADDR }
+
+  fn load(&mut self, addr: u16) -> i16 {
+    match addr {
+      ADSR => READY,
+      _ => 0
+    }
+  }
+
+  fn store(&mut self, addr: u16, value: i16) {
+    if addr == ADDR {
+      let _ = self.sink.write_all(&[value as u8]);
+      let _ = self.sink.flush();
+    }
+  }
+
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerClock { Instructions, HostMillis }
+
+// Timer status/interval registers.  Writing the interval restarts the
+// timer; reading the status reports READY once the interval has elapsed,
+// measured in executed instructions or host milliseconds, and restarts it.
+pub struct Timer {
+  clock: TimerClock,
+  interval: u16,
+  executed: u64,
+  started: Instant
+}
+
+impl Timer {
+
+  pub fn new(clock: TimerClock) -> Timer {
+    Timer{ clock: clock, interval: 0, executed: 0, started: Instant::now() }
+  }
+
+  fn restart(&mut self) {
+    self.executed = 0;
+    self.started = Instant::now()
+  }
+
+  fn expired(&self) -> bool {
+    self.interval != 0 && match self.clock {
+      TimerClock::Instructions => self.executed >= self.interval as u64,
+      TimerClock::HostMillis =>
+        self.started.elapsed() >= Duration::from_millis(self.interval as u64)
+    }
+  }
+
+}
+
+impl Device for Timer {
+
+  fn claims(&self, addr: u16) -> bool { addr == TSR || addr == TIR }
+
+  fn load(&mut self, addr: u16) -> i16 {
+    match addr {
+      TSR => if self.expired() { self.restart(); READY } else { 0 },
+      TIR => self.interval as i16,
+      _ => 0
+    }
+  }
+
+  fn store(&mut self, addr: u16, value: i16) {
+    if addr == TIR {
+      self.interval = value as u16;
+      self.restart()
+    }
+  }
+
+  fn tick(&mut self, _memory: &Memory<i16>) {
+    self.executed += 1
+  }
+
+  // A host-time timer restarts its interval on restore
+  fn save(&self, out: &mut Write) -> Result<(), io::Error> {
+    try!(out.write_u16::<BigEndian>(self.interval));
+    out.write_u64::<BigEndian>(self.executed)
+  }
+
+  fn restore(&mut self, input: &mut Read) -> Result<(), io::Error> {
+    self.interval = try!(input.read_u16::<BigEndian>());
+    self.executed = try!(input.read_u64::<BigEndian>());
+    self.started = Instant::now();
+    Ok(())
+  }
+
+}
+
+#[test]
+fn keyboard_unit_tests () {
+  let mut kbd = Keyboard::new();
+  assert!(kbd.load(KBSR) == 0);
+  kbd.push_str("hi");
+  assert!(kbd.load(KBSR) == READY);
+  assert!(kbd.load(KBDR) == 'h' as i16);
+  assert!(kbd.load(KBDR) == 'i' as i16);
+  assert!(kbd.load(KBSR) == 0);
+
+  // Host input arrives asynchronously, so poll for it
+  fn wait_ready(kbd: &mut Keyboard) -> bool {
+    for _ in 0..1000 {
+      if kbd.load(KBSR) == READY { return true }
+      thread::sleep(Duration::from_millis(1))
+    }
+    false
+  }
+
+  let mut kbd = Keyboard::from_reader(Box::new("ok".as_bytes()));
+  assert!(wait_ready(&mut kbd));
+  assert!(kbd.load(KBDR) == 'o' as i16);
+  assert!(wait_ready(&mut kbd));
+  assert!(kbd.load(KBDR) == 'k' as i16);
+  assert!(kbd.load(KBSR) == 0);
+
+  // A reader with nothing to deliver must not stall a status read
+  struct Blocking(Receiver<u8>);
+  impl Read for Blocking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      match self.0.recv() {
+        Ok(byte) => { buf[0] = byte; Ok(1) },
+        Err(_) => Ok(0)
+      }
+    }
+  }
+  let (host, pending) = channel();
+  let mut kbd = Keyboard::from_reader(Box::new(Blocking(pending)));
+  assert!(kbd.load(KBSR) == 0);
+  assert!(kbd.load(KBSR) == 0);
+  host.send(b'x').unwrap();
+  assert!(wait_ready(&mut kbd));
+  assert!(kbd.load(KBDR) == 'x' as i16);
+
+  // The host takes whole lines the program has left unread
+  let mut kbd = Keyboard::from_reader(Box::new("c\nhi".as_bytes()));
+  assert!(kbd.host_line() == Some("c\n".to_string()));
+  assert!(wait_ready(&mut kbd));
+  assert!(kbd.load(KBDR) == 'h' as i16);
+  assert!(kbd.host_line() == None);
+  assert!(kbd.load(KBDR) == 'i' as i16);
+}
+
+#[test]
+fn display_unit_tests () {
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use architecture::*;
+  use processor::*;
+
+  let display = Rc::new(RefCell::new(AsciiDisplay::new(Vec::new())));
+  let mut cpu = boot_program(&[]);
+  cpu.bus.attach(Box::new(display.clone()));
+  cpu.priv_status = true;
+  cpu.regfile[R1] = ADDR as i16;
+
+  assert!(cpu.bus.load(&cpu.memory, ADSR) == READY);
+  for c in "ok\n".bytes() {
+    cpu.regfile[R0] = c as i16;
+    cpu.execute(InsnGen::STR(R0, R1, IMM6{value: 0})).unwrap();
+  }
+  assert!(display.borrow().sink() == &b"ok\n".to_vec());
+  assert!(cpu.memory[ADDR as usize] == 0);
+}
+
+#[test]
+fn timer_unit_tests () {
+  let memory: Memory<i16> = box [0;0x10000];
+  let mut timer = Timer::new(TimerClock::Instructions);
+  timer.tick(&memory);
+  assert!(timer.load(TSR) == 0);
+
+  timer.store(TIR, 3);
+  assert!(timer.load(TIR) == 3);
+  timer.tick(&memory);
+  timer.tick(&memory);
+  assert!(timer.load(TSR) == 0);
+  timer.tick(&memory);
+  assert!(timer.load(TSR) == READY);
+  assert!(timer.load(TSR) == 0);
+}
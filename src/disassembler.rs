@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use architecture::*;
+use assm_data::*;
+use controller::*;
+
+// Where control goes if a PC-relative or absolute jump at `addr` is taken
+pub fn jump_target(addr: u16, insn: Insn) -> Option<u16> {
+  match insn {
+    InsnGen::BR(_, i) => Some(addr.wrapping_add(1).wrapping_add(i.value as u16)),
+    InsnGen::JMP(i) => Some(addr.wrapping_add(1).wrapping_add(i.value as u16)),
+    InsnGen::JSR(i) => Some((addr & 0x8000) | (i.value << 4) as u16),
+    _ => None
+  }
+}
+
+// Labels keyed by address.  Where several labels share an address the
+// alphabetically first is used, so output is stable.
+pub fn labels_by_addr(labels: &HashMap<Label, (Section, u16)>) -> HashMap<u16, Label> {
+  let mut by_addr: HashMap<u16, Label> = HashMap::new();
+  for (label, &(_, addr)) in labels.iter() {
+    let replace = match by_addr.get(&addr) {
+      Some(existing) => label < existing,
+      None => true
+    };
+    if replace { by_addr.insert(addr, label.clone()); }
+  }
+  by_addr
+}
+
+// Like Display, but jump targets are shown as labels where one exists
+pub fn disassemble(addr: u16, insn: Insn, labels: &HashMap<u16, Label>) -> String {
+  match jump_target(addr, insn).and_then(|target| labels.get(&target)) {
+    Some(label) => match insn {
+      InsnGen::BR(cc, _) => format!("BR{} {}", cc_suffix(cc), label),
+      InsnGen::JMP(_) => format!("JMP {}", label),
+      _ => format!("JSR {}", label)
+    },
+    None => format!("{}", insn)
+  }
+}
+
+// Words that do not decode are shown as data
+pub fn disassemble_word(addr: u16, word: i16, labels: &HashMap<u16, Label>) -> String {
+  match (word as u16).decode() {
+    Ok(insn) => disassemble(addr, insn, labels),
+    Err(_) => format!(".FILL x{:04X}", word as u16)
+  }
+}
+
+// A word is data if the nearest label at or before it in the same region
+// is a data label, and otherwise if it lies in a data region
+pub fn section_at(addr: u16, labels: &HashMap<Label, (Section, u16)>) -> Section {
+  let nearest = labels.values()
+    .filter(|&&(_, a)| a <= addr && region(a) == region(addr))
+    .fold(None, |best: Option<(Section, u16)>, &(section, a)| match best {
+      Some((_, b)) if b >= a => best,
+      _ => Some((section, a))
+    });
+  match (nearest, region(addr)) {
+    (Some((section, _)), _) => section,
+    (None, Region::UserCode) | (None, Region::OSCode) => Section::CODE,
+    (None, _) => Section::DATA
+  }
+}
+
+// Assembly listing of the nonzero words in memory, with .ADDR directives
+// over gaps and .CODE/.DATA wherever the section changes
+pub fn disassemble_image(assm_data: &AssmData<i16>) -> Vec<String> {
+  let by_addr = labels_by_addr(&assm_data.labels);
+  let mut lines = Vec::new();
+  let mut section: Option<Section> = None;
+  let mut next: Option<u16> = None;
+  for addr in 0..0x10000 {
+    let addr = addr as u16;
+    let word = assm_data.memory[addr as usize];
+    let label = by_addr.get(&addr);
+    if word == 0 && label.is_none() { continue }
+
+    let here = section_at(addr, &assm_data.labels);
+    if section != Some(here) {
+      lines.push(match here { Section::CODE => ".CODE", Section::DATA => ".DATA" }.to_string());
+      section = Some(here);
+      next = None;
+    }
+    if next != Some(addr) {
+      lines.push(format!(".ADDR x{:04X}", addr));
+    }
+    next = Some(addr.wrapping_add(1));
+    if let Some(label) = label {
+      lines.push(label.clone());
+    }
+    lines.push(match here {
+      Section::CODE => format!("  {}", disassemble_word(addr, word, &by_addr)),
+      Section::DATA => format!("  .FILL x{:04X}", word as u16)
+    });
+  }
+  lines
+}
+
+#[test]
+fn disassembler_unit_tests () {
+  let mut labels: HashMap<Label, (Section, u16)> = HashMap::new();
+  labels.insert("LOOP".to_string(), (Section::CODE, 1));
+  labels.insert("TABLE".to_string(), (Section::DATA, 0x1000));
+  let by_addr = labels_by_addr(&labels);
+
+  let add: Insn = InsnGen::ADDi(R1, R1, IMM5{value: -1});
+  let trap: Insn = InsnGen::TRAP(UIMM8{value: 0x25});
+  assert!(add.to_string() == "ADD R1, R1, #-1");
+  assert!(trap.to_string() == "TRAP x25");
+  assert!(disassemble_word(2, 0x03FE, &by_addr) == "BRp LOOP");
+  assert!(disassemble_word(3, 0x0FFE, &by_addr) == "BRnzp #-2");
+  assert!(disassemble_word(3, 0x3000, &by_addr) == ".FILL x3000");
+
+  assert!(section_at(0x0FFF, &labels) == Section::CODE);
+  assert!(section_at(0x1001, &labels) == Section::DATA);
+  assert!(section_at(0x2000, &labels) == Section::DATA);
+  assert!(section_at(0x8000, &labels) == Section::CODE);
+}
@@ -1,6 +1,11 @@
 
 use architecture::*;
 
+/// Encodes an instruction as its machine word.
+///
+/// Don't-care bits are zero, except that `HICONST` sets bit 8 as PennSim
+/// does.  Fields are masked to their width, so only in-range fields survive
+/// a round trip through `controller::Controller::decode`.
 pub fn encode_insn(insn: Insn) -> i16 {
   match insn {
     InsnGen::NOP => 
@@ -75,4 +80,94 @@ pub fn encode_insn(insn: Insn) -> i16 {
     InsnGen::TRAP(u) =>
       (0b1111 << 12) | (u.value as i16 & 0xFF)
   }
-}
\ No newline at end of file
+}
+
+// Bits of a word that decoding reads; the rest are don't-cares
+#[cfg(test)]
+fn care_mask(word: u16) -> u16 {
+  match (word >> 12, word) {
+    (0b0000, w) if w & 0x0E00 == 0 => 0xFE00,
+    (0b0010, w) if w & 0x0100 == 0 => 0xFF87,
+    (0b0100, w) | (0b1100, w) if w & 0x0800 == 0 => 0xF9C0,
+    (0b0101, w) if w & 0x0038 == 0x0008 => 0xFFF8,
+    (0b1000, _) => 0xF000,
+    (0b1010, w) if w & 0x0030 == 0x0030 => 0xFFF7,
+    (0b1101, _) => 0xFEFF,
+    (0b1111, _) => 0xF0FF,
+    _ => 0xFFFF
+  }
+}
+
+#[test]
+fn decode_encode_unit_tests () {
+  use controller::*;
+
+  for word in 0..0x10000u32 {
+    let word = word as u16;
+    if let Ok(insn) = word.decode() {
+      let encoded = encode_insn(insn) as u16;
+      assert!((encoded ^ word) & care_mask(word) == 0,
+              "{:016b} decodes to {:?} but encodes as {:016b}", word, insn, encoded);
+      assert!(encoded.decode() == Ok(insn));
+    }
+  }
+}
+
+#[test]
+fn encode_decode_unit_tests () {
+  use controller::*;
+
+  let check = |insn: Insn| {
+    let word = encode_insn(insn) as u16;
+    assert!(word.decode() == Ok(insn), "{:?} encodes as {:016b}", insn, word);
+  };
+
+  check(InsnGen::NOP);
+  check(InsnGen::RTI);
+  for cc in 1..8 {
+    for i in -256..256 { check(InsnGen::BR(cc, IMM9{value: i})) }
+  }
+  for i in -1024..1024 {
+    check(InsnGen::JSR(IMM11{value: i}));
+    check(InsnGen::JMP(IMM11{value: i}));
+  }
+  for u in 0..256 { check(InsnGen::TRAP(UIMM8{value: u})) }
+
+  for rd in 0..8 {
+    for i in -256..256 { check(InsnGen::CONST(rd, IMM9{value: i})) }
+    for u in 0..256 { check(InsnGen::HICONST(rd, UIMM8{value: u})) }
+    for i in -64..64 { check(InsnGen::CMPi(rd, IMM7{value: i})) }
+    for u in 0..128 { check(InsnGen::CMPiu(rd, UIMM7{value: u})) }
+    check(InsnGen::JSRr(rd));
+    check(InsnGen::JMPr(rd));
+
+    for rs in 0..8 {
+      check(InsnGen::CMP(rd, rs));
+      check(InsnGen::CMPu(rd, rs));
+      check(InsnGen::NOT(rd, rs));
+      for i in -16..16 {
+        check(InsnGen::ADDi(rd, rs, IMM5{value: i}));
+        check(InsnGen::ANDi(rd, rs, IMM5{value: i}));
+      }
+      for i in -32..32 {
+        check(InsnGen::LDR(rd, rs, IMM6{value: i}));
+        check(InsnGen::STR(rd, rs, IMM6{value: i}));
+      }
+      for u in 0..16 {
+        check(InsnGen::SLL(rd, rs, UIMM4{value: u}));
+        check(InsnGen::SRA(rd, rs, UIMM4{value: u}));
+        check(InsnGen::SRL(rd, rs, UIMM4{value: u}));
+      }
+      for rt in 0..8 {
+        check(InsnGen::ADD(rd, rs, rt));
+        check(InsnGen::MUL(rd, rs, rt));
+        check(InsnGen::SUB(rd, rs, rt));
+        check(InsnGen::DIV(rd, rs, rt));
+        check(InsnGen::AND(rd, rs, rt));
+        check(InsnGen::OR(rd, rs, rt));
+        check(InsnGen::XOR(rd, rs, rt));
+        check(InsnGen::MOD(rd, rs, rt));
+      }
+    }
+  }
+}
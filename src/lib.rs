@@ -10,6 +10,13 @@ extern crate byteorder;
 pub mod architecture;
 pub mod assembler;
 pub mod assm_data;
-mod controller;
-mod encoder;
+pub mod bus;
+pub mod controller;
+pub mod debugger;
+pub mod devices;
+pub mod disassembler;
+pub mod encoder;
 pub mod processor;
+pub mod snapshot;
+pub mod trace;
+pub mod video;
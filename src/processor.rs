@@ -1,15 +1,25 @@
 use architecture::*;
 use assm_data::*;
+use bus::*;
 use controller::*;
 use std::convert::From;
 use std::cmp::Ordering;
 
+// Which architectural state the last executed instruction wrote
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Writeback {
+  pub reg: Option<(RName, i16)>,
+  pub nzp: bool
+}
+
 pub struct CPU {
   pub regfile: [i16; 8],
   pub priv_status: bool,
   pub pc: u16,
   pub nzp: CC,
-  pub memory: Memory<i16>
+  pub memory: Memory<i16>,
+  pub bus: Bus,
+  pub writeback: Writeback
 }
 
 pub trait Simulate {
@@ -17,8 +27,13 @@ pub trait Simulate {
   fn step(&mut self) -> Result<(), CPUError>;
 }
 
-#[derive(Debug)]
-pub enum CPUError { DecodeError(DecodeError), Unauthorized }
+#[derive(Debug, Eq, PartialEq)]
+pub enum CPUError {
+  DecodeError(DecodeError),
+  IllegalFetch(u16),
+  IllegalLoad(u16),
+  IllegalStore(u16)
+}
 
 impl From<DecodeError> for CPUError {
   fn from(err: DecodeError) -> CPUError {
@@ -34,128 +49,283 @@ fn from_ordering(ord: Ordering) -> CC {
   }
 }
 
+// Division by zero yields zero, as in PennSim, rather than trapping
+fn divide(a: i16, b: i16, op: fn(i16, i16) -> i16) -> i16 {
+  if b == 0 { 0 } else { op(a, b) }
+}
+
 pub fn boot(assm_data: AssmData<i16>) -> CPU {
   CPU{
     regfile: [0;8],
     priv_status: false,
     pc: 0,
     nzp: Z,
-    memory: assm_data.memory
+    memory: assm_data.memory,
+    bus: Bus::new(),
+    writeback: Writeback{ reg: None, nzp: false }
   }
 }
 
+// TRAP x25 halts the machine by convention
+pub const HALT : u16 = 0x25;
+
+impl CPU {
+
+  pub fn halted(&self) -> bool {
+    (self.memory[self.pc as usize] as u16).decode() == Ok(InsnGen::TRAP(UIMM8{value: HALT}))
+  }
+
+  fn can_fetch(&self, addr: u16) -> bool {
+    match region(addr) {
+      Region::UserCode => true,
+      Region::OSCode => self.priv_status,
+      _ => false
+    }
+  }
+
+  fn can_access(&self, addr: u16) -> bool {
+    match region(addr) {
+      Region::UserData => true,
+      Region::OSData => self.priv_status,
+      _ => false
+    }
+  }
+
+}
+
 impl Simulate for CPU {
   
   fn execute(&mut self, insn: Insn) -> Result<(), CPUError> {
     let mut pc_incr = true;
-    match insn {
-      InsnGen::NOP => {},
+    let write: Option<(RName, i16)> = match insn {
+      InsnGen::NOP => None,
       
-      InsnGen::BR(cc, offset) => 
+      InsnGen::BR(cc, offset) => {
         if cc & self.nzp != 0 { 
-          self.pc += offset.value as u16 
-        },
+          self.pc = self.pc.wrapping_add(offset.value as u16)
+        };
+        None
+      },
       
       InsnGen::ADD(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] + self.regfile[rt],
+        Some((rd, self.regfile[rs].wrapping_add(self.regfile[rt]))),
       InsnGen::MUL(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] * self.regfile[rt],
+        Some((rd, self.regfile[rs].wrapping_mul(self.regfile[rt]))),
       InsnGen::SUB(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] - self.regfile[rt],
+        Some((rd, self.regfile[rs].wrapping_sub(self.regfile[rt]))),
       InsnGen::DIV(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] / self.regfile[rt],
+        Some((rd, divide(self.regfile[rs], self.regfile[rt], i16::wrapping_div))),
       InsnGen::ADDi(rd, rs, n) => 
-        self.regfile[rd] = self.regfile[rs] + n.value,
+        Some((rd, self.regfile[rs].wrapping_add(n.value))),
       
-      InsnGen::CMP(rd, rt) => 
-        self.nzp = from_ordering(self.regfile[rd].cmp(&self.regfile[rt])),
-      InsnGen::CMPu(rd, rt) => 
+      InsnGen::CMP(rd, rt) => { 
+        self.nzp = from_ordering(self.regfile[rd].cmp(&self.regfile[rt]));
+        None
+      },
+      InsnGen::CMPu(rd, rt) => { 
         self.nzp = from_ordering((self.regfile[rd] as u16)
-                    .cmp(&(self.regfile[rt] as u16))),
-      InsnGen::CMPi(rd, test) => 
-        self.nzp = from_ordering(self.regfile[rd].cmp(&test.value)),
-      InsnGen::CMPiu(rd, test) => 
-        self.nzp = from_ordering((self.regfile[rd] as u16).cmp(&test.value)),
+                    .cmp(&(self.regfile[rt] as u16)));
+        None
+      },
+      InsnGen::CMPi(rd, test) => { 
+        self.nzp = from_ordering(self.regfile[rd].cmp(&test.value));
+        None
+      },
+      InsnGen::CMPiu(rd, test) => { 
+        self.nzp = from_ordering((self.regfile[rd] as u16).cmp(&test.value));
+        None
+      },
       
       InsnGen::JSR(target) => { 
         pc_incr = false; 
-        self.regfile[R7] = self.pc as i16 + 1; 
-        self.pc = (self.pc & 0x8000) | (target.value << 4) as u16 
+        let link = self.pc.wrapping_add(1) as i16;
+        self.pc = (self.pc & 0x8000) | (target.value << 4) as u16;
+        Some((R7, link))
       }
       InsnGen::JSRr(rs) => { 
         pc_incr = false; 
-        self.regfile[R7] = self.pc as i16 + 1; 
-        self.pc = self.regfile[rs] as u16
+        let link = self.pc.wrapping_add(1) as i16;
+        self.pc = self.regfile[rs] as u16;
+        Some((R7, link))
       }
       
       InsnGen::AND(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] & self.regfile[rt],
+        Some((rd, self.regfile[rs] & self.regfile[rt])),
       InsnGen::NOT(rd, rs)     => 
-        self.regfile[rd] = !self.regfile[rs],
+        Some((rd, !self.regfile[rs])),
       InsnGen::OR (rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] | self.regfile[rt],
+        Some((rd, self.regfile[rs] | self.regfile[rt])),
       InsnGen::XOR(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] ^ self.regfile[rt],
+        Some((rd, self.regfile[rs] ^ self.regfile[rt])),
       InsnGen::ANDi(rd, rs, n) => 
-        self.regfile[rd] = self.regfile[rs] & (n.value as i16),
+        Some((rd, self.regfile[rs] & (n.value as i16))),
       
       InsnGen::LDR(rd, rs, offset) => {
-        let addr = (self.regfile[rs] as i16 + offset.value) as usize;
-        if !self.priv_status && addr >= 0x8000 { 
-          return Err(CPUError::Unauthorized) 
+        let addr = self.regfile[rs].wrapping_add(offset.value) as u16;
+        if !self.can_access(addr) { 
+          return Err(CPUError::IllegalLoad(addr)) 
         };
-        self.regfile[rd] = self.memory[addr]
+        Some((rd, self.bus.load(&self.memory, addr)))
       },
       InsnGen::STR(rd, rs, offset) =>  {
-        let addr = (self.regfile[rs] as i16 + offset.value) as usize;
-        if !self.priv_status && addr >= 0x8000 { 
-          return Err(CPUError::Unauthorized) 
+        let addr = self.regfile[rs].wrapping_add(offset.value) as u16;
+        if !self.can_access(addr) { 
+          return Err(CPUError::IllegalStore(addr)) 
         };
-        self.memory[addr] = self.regfile[rd]
+        self.bus.store(&mut self.memory, addr, self.regfile[rd]);
+        None
       },
       
       InsnGen::RTI => { 
         pc_incr = false; 
         self.pc = self.regfile[R7] as u16; 
-        self.priv_status = false 
+        self.priv_status = false;
+        None
       },
       
-      InsnGen::CONST(rd, c) => self.regfile[rd] = c.value,
+      InsnGen::CONST(rd, c) => Some((rd, c.value)),
       
       InsnGen::SLL(rd, rs, amount) => 
-        self.regfile[rd] = self.regfile[rs] << amount.value,
+        Some((rd, self.regfile[rs].wrapping_shl(amount.value as u32))),
       InsnGen::SRA(rd, rs, amount) => 
-        self.regfile[rd] = ((self.regfile[rs] as u16) >> amount.value) as i16,
+        Some((rd, self.regfile[rs].wrapping_shr(amount.value as u32))),
       InsnGen::SRL(rd, rs, amount) => 
-        self.regfile[rd] = self.regfile[rs] >> amount.value,
+        Some((rd, (self.regfile[rs] as u16).wrapping_shr(amount.value as u32) as i16)),
       InsnGen::MOD(rd, rs, rt) => 
-        self.regfile[rd] = self.regfile[rs] % self.regfile[rt],
+        Some((rd, divide(self.regfile[rs], self.regfile[rt], i16::wrapping_rem))),
       
       InsnGen::JMPr(rs) => {
         pc_incr = false;
-        self.pc = self.regfile[rs] as u16
+        self.pc = self.regfile[rs] as u16;
+        None
+      },
+      InsnGen::JMP(target) => {
+        self.pc = self.pc.wrapping_add(target.value as u16);
+        None
       },
-      InsnGen::JMP(target) => self.pc = ((self.pc as i16) + target.value) as u16,
       
       InsnGen::HICONST(rd, c) => 
-        self.regfile[rd] = (self.regfile[rd] & 0xFF) | ((c.value << 8) as i16),
+        Some((rd, (self.regfile[rd] & 0xFF) | ((c.value << 8) as i16))),
       
       InsnGen::TRAP(target) => {
         pc_incr = false;
+        let link = self.pc.wrapping_add(1) as i16;
         self.pc = 0x8000 | target.value;
         self.priv_status = true;
+        Some((R7, link))
+      }
+    };
+    
+    // Every register write also sets NZP from the value written
+    if let Some((rd, value)) = write {
+      self.regfile[rd] = value;
+      self.nzp = from_ordering(value.cmp(&0))
+    };
+    
+    self.writeback = Writeback{
+      reg: write,
+      nzp: write.is_some() || match insn {
+        InsnGen::CMP(_, _) | InsnGen::CMPu(_, _) |
+        InsnGen::CMPi(_, _) | InsnGen::CMPiu(_, _) => true,
+        _ => false
       }
     };
     
-    if pc_incr { self.pc += 1 };
+    if pc_incr { self.pc = self.pc.wrapping_add(1) };
     
     Ok(())
   }
   
   fn step(&mut self) -> Result<(), CPUError> {
+    self.bus.clear_accesses();
+    if !self.can_fetch(self.pc) {
+      return Err(CPUError::IllegalFetch(self.pc))
+    }
     let raw_insn = self.memory[self.pc as usize];
     let insn = try!((raw_insn as u16).decode());
-    self.execute(insn)
+    try!(self.execute(insn));
+    self.bus.tick(&self.memory);
+    Ok(())
   }
   
 }
+
+// A CPU with `words` loaded from address zero
+#[cfg(test)]
+pub fn boot_program(words: &[u16]) -> CPU {
+  use std::collections::HashMap;
+
+  let mut memory: Memory<i16> = box [0;0x10000];
+  for (addr, &word) in words.iter().enumerate() {
+    memory[addr] = word as i16;
+  }
+  boot(AssmData{ memory: memory, labels: HashMap::new(), heap: words.len() as u16 })
+}
+
+#[test]
+fn protection_unit_tests () {
+  let mut cpu = boot_program(&[]);
+
+  cpu.regfile[R1] = USER_DATA as i16;
+  assert!(cpu.execute(InsnGen::STR(R0, R1, IMM6{value: 0})) == Ok(()));
+  cpu.regfile[R1] = 0x1000;
+  assert!(cpu.execute(InsnGen::STR(R0, R1, IMM6{value: 0})) == Err(CPUError::IllegalStore(0x1000)));
+  cpu.regfile[R1] = OS_DATA as i16;
+  assert!(cpu.execute(InsnGen::LDR(R0, R1, IMM6{value: 1})) == Err(CPUError::IllegalLoad(0xA001)));
+
+  cpu.pc = OS_CODE;
+  assert!(cpu.step() == Err(CPUError::IllegalFetch(OS_CODE)));
+  cpu.priv_status = true;
+  assert!(cpu.step() == Ok(()));
+  assert!(cpu.execute(InsnGen::LDR(R0, R1, IMM6{value: 1})) == Ok(()));
+
+  cpu.pc = USER_DATA;
+  assert!(cpu.step() == Err(CPUError::IllegalFetch(USER_DATA)));
+}
+
+#[test]
+fn nzp_unit_tests () {
+  let mut cpu = boot_program(&[]);
+
+  cpu.execute(InsnGen::CONST(R1, IMM9{value: 2})).unwrap();
+  assert!(cpu.nzp == P);
+  cpu.execute(InsnGen::ADDi(R1, R1, IMM5{value: -1})).unwrap();
+  assert!(cpu.nzp == P);
+  cpu.execute(InsnGen::ADDi(R1, R1, IMM5{value: -1})).unwrap();
+  assert!(cpu.nzp == Z);
+  cpu.execute(InsnGen::NOT(R2, R1)).unwrap();
+  assert!(cpu.regfile[R2] == -1 && cpu.nzp == N);
+
+  cpu.pc = 0x10;
+  cpu.execute(InsnGen::TRAP(UIMM8{value: 0x25})).unwrap();
+  assert!(cpu.regfile[R7] == 0x11 && cpu.nzp == P);
+  assert!(cpu.pc == 0x8025 && cpu.priv_status);
+}
+
+#[test]
+fn wrapping_unit_tests () {
+  let mut cpu = boot_program(&[]);
+
+  cpu.regfile[R1] = 0x7FFF;
+  cpu.execute(InsnGen::ADDi(R2, R1, IMM5{value: 1})).unwrap();
+  assert!(cpu.regfile[R2] == -0x8000 && cpu.nzp == N);
+  cpu.execute(InsnGen::MUL(R2, R1, R1)).unwrap();
+  assert!(cpu.regfile[R2] == 1);
+  cpu.execute(InsnGen::DIV(R2, R1, R0)).unwrap();
+  assert!(cpu.regfile[R2] == 0 && cpu.nzp == Z);
+  cpu.execute(InsnGen::MOD(R2, R1, R0)).unwrap();
+  assert!(cpu.regfile[R2] == 0);
+
+  cpu.regfile[R1] = -16;
+  cpu.execute(InsnGen::SRA(R2, R1, UIMM4{value: 2})).unwrap();
+  assert!(cpu.regfile[R2] == -4);
+  cpu.execute(InsnGen::SRL(R2, R1, UIMM4{value: 12})).unwrap();
+  assert!(cpu.regfile[R2] == 0xF);
+
+  cpu.pc = 0xFFFF;
+  cpu.execute(InsnGen::NOP).unwrap();
+  assert!(cpu.pc == 0);
+  cpu.nzp = P;
+  cpu.execute(InsnGen::BR(P, IMM9{value: -1})).unwrap();
+  assert!(cpu.pc == 0);
+}
@@ -0,0 +1,125 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use assm_data::*;
+use processor::*;
+
+// A snapshot is the magic, a format version, the register file, PC, NZP,
+// privilege bit, all of memory, and then the state of each attached device
+pub const SNAPSHOT_MAGIC : &'static [u8; 4] = b"LC4S";
+pub const SNAPSHOT_VERSION : u16 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn write_snapshot(cpu: &CPU, out: &mut Write) -> Result<(), io::Error> {
+  try!(out.write_all(SNAPSHOT_MAGIC));
+  try!(out.write_u16::<BigEndian>(SNAPSHOT_VERSION));
+  for &r in cpu.regfile.iter() {
+    try!(out.write_i16::<BigEndian>(r));
+  }
+  try!(out.write_u16::<BigEndian>(cpu.pc));
+  try!(out.write_u8(cpu.nzp));
+  try!(out.write_u8(cpu.priv_status as u8));
+  for &word in cpu.memory.iter() {
+    try!(out.write_i16::<BigEndian>(word));
+  }
+  cpu.bus.save(out)
+}
+
+// Restores into an existing CPU, whose bus must have the same devices
+// attached as the one that was saved.  Nothing is changed unless the whole
+// snapshot reads back successfully.
+pub fn read_snapshot(cpu: &mut CPU, input: &mut Read) -> Result<(), io::Error> {
+  let mut magic = Vec::new();
+  try!(input.take(4).read_to_end(&mut magic));
+  if &magic[..] != &SNAPSHOT_MAGIC[..] {
+    return Err(invalid("not an LC4 snapshot"))
+  }
+  if try!(input.read_u16::<BigEndian>()) != SNAPSHOT_VERSION {
+    return Err(invalid("unsupported snapshot version"))
+  }
+  let mut regfile = [0i16; 8];
+  for r in regfile.iter_mut() {
+    *r = try!(input.read_i16::<BigEndian>());
+  }
+  let pc = try!(input.read_u16::<BigEndian>());
+  let nzp = try!(input.read_u8());
+  let priv_status = try!(input.read_u8()) != 0;
+  let mut memory: Memory<i16> = box [0;0x10000];
+  for word in memory.iter_mut() {
+    *word = try!(input.read_i16::<BigEndian>());
+  }
+  try!(cpu.bus.restore(input));
+
+  cpu.regfile = regfile;
+  cpu.pc = pc;
+  cpu.nzp = nzp;
+  cpu.priv_status = priv_status;
+  cpu.memory = memory;
+  Ok(())
+}
+
+pub fn save_snapshot(cpu: &CPU, path: &str) -> Result<(), io::Error> {
+  let file = try!(File::create(&Path::new(path)));
+  let mut out = BufWriter::new(file);
+  try!(write_snapshot(cpu, &mut out));
+  out.flush()
+}
+
+pub fn load_snapshot(cpu: &mut CPU, path: &str) -> Result<(), io::Error> {
+  let file = try!(File::open(&Path::new(path)));
+  read_snapshot(cpu, &mut BufReader::new(file))
+}
+
+pub fn is_snapshot_file(path: &str) -> bool {
+  let mut magic = Vec::new();
+  match File::open(&Path::new(path)) {
+    Ok(file) => file.take(4).read_to_end(&mut magic).is_ok() && &magic[..] == &SNAPSHOT_MAGIC[..],
+    Err(_) => false
+  }
+}
+
+#[test]
+fn snapshot_unit_tests () {
+  use architecture::*;
+  use devices::*;
+
+  let mut cpu = boot_program(&[]);
+  let mut kbd = Keyboard::new();
+  kbd.push_str("ab");
+  cpu.bus.attach(Box::new(kbd));
+  cpu.regfile[R3] = -7;
+  cpu.pc = 0x8200;
+  cpu.nzp = N;
+  cpu.priv_status = true;
+  cpu.memory[0xFFFF] = 42;
+
+  let mut image = Vec::new();
+  write_snapshot(&cpu, &mut image).unwrap();
+
+  let mut restored = boot_program(&[]);
+  restored.bus.attach(Box::new(Keyboard::new()));
+  read_snapshot(&mut restored, &mut &image[..]).unwrap();
+  assert!(restored.regfile == cpu.regfile);
+  assert!(restored.pc == 0x8200 && restored.nzp == N && restored.priv_status);
+  assert!(restored.memory[0xFFFF] == 42);
+  assert!(restored.bus.load(&restored.memory, KBDR) == 'a' as i16);
+
+  // Failed restores leave the machine as it was
+  let mut bare = boot_program(&[0x1203]);
+  bare.regfile[R3] = 5;
+  bare.pc = 0x10;
+  assert!(read_snapshot(&mut bare, &mut &image[..]).is_err());
+  assert!(read_snapshot(&mut bare, &mut &b"LC4X"[..]).is_err());
+  assert!(bare.regfile == [0, 0, 0, 5, 0, 0, 0, 0] && bare.pc == 0x10 && bare.nzp == Z);
+  assert!(bare.memory[0] == 0x1203 && bare.memory[0xFFFF] == 0 && !bare.priv_status);
+
+  let truncated = &image[..image.len() - 1];
+  assert!(read_snapshot(&mut restored, &mut &truncated[..]).is_err());
+  assert!(restored.bus.load(&restored.memory, KBDR) == 'b' as i16);
+}
@@ -0,0 +1,217 @@
+use std::convert::From;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use architecture::*;
+use bus::*;
+use processor::*;
+
+// One retired instruction, in the columns of the PennSim tracer: PC, the
+// instruction word in binary, register write enable/number/value, NZP
+// write enable/value, and data memory write enable/address/value.  Loads
+// report their address and value with the write enable clear.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceLine {
+  pub pc: u16,
+  pub insn: u16,
+  pub reg: Option<(RName, i16)>,
+  pub nzp: Option<CC>,
+  pub data: Option<(bool, u16, i16)>
+}
+
+impl TraceLine {
+
+  // Parses one line in the format written by Display
+  pub fn parse(line: &str) -> Option<TraceLine> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 10 {
+      return None
+    }
+    let hex = |i: usize| u16::from_str_radix(fields[i], 16).ok();
+    let flag = |i: usize| match fields[i] { "0" => Some(false), "1" => Some(true), _ => None };
+    let (pc, insn) = match (hex(0), u16::from_str_radix(fields[1], 2).ok()) {
+      (Some(pc), Some(insn)) => (pc, insn),
+      _ => return None
+    };
+    let reg = match (flag(2), fields[3].parse::<RName>().ok(), hex(4)) {
+      (Some(false), Some(_), Some(_)) => None,
+      (Some(true), Some(r), Some(value)) if r < 8 => Some((r, value as i16)),
+      _ => return None
+    };
+    let nzp = match (flag(5), fields[6].parse::<CC>().ok()) {
+      (Some(false), Some(_)) => None,
+      (Some(true), Some(nzp)) if nzp < 8 => Some(nzp),
+      _ => return None
+    };
+    let data = match (flag(7), hex(8), hex(9)) {
+      (Some(false), Some(0), Some(0)) => None,
+      (Some(we), Some(addr), Some(value)) => Some((we, addr, value as i16)),
+      _ => return None
+    };
+    Some(TraceLine{ pc: pc, insn: insn, reg: reg, nzp: nzp, data: data })
+  }
+
+  // Two lines agree if they retire the same instruction with the same
+  // writes.  Load addresses and values are informational only.
+  pub fn agrees(&self, other: &TraceLine) -> bool {
+    let store = |line: &TraceLine| match line.data {
+      Some((true, addr, value)) => Some((addr, value)),
+      _ => None
+    };
+    self.pc == other.pc && self.insn == other.insn && self.reg == other.reg &&
+      self.nzp == other.nzp && store(self) == store(other)
+  }
+
+  // Describes the instruction the CPU just executed from `pc`
+  pub fn retired(pc: u16, insn: u16, cpu: &CPU) -> TraceLine {
+    TraceLine{
+      pc: pc,
+      insn: insn,
+      reg: cpu.writeback.reg,
+      nzp: if cpu.writeback.nzp { Some(cpu.nzp) } else { None },
+      data: cpu.bus.accesses().last().map(|&access| match access {
+        Access::Load(addr, value) => (false, addr, value),
+        Access::Store(addr, _, value) => (true, addr, value)
+      })
+    }
+  }
+
+}
+
+impl fmt::Display for TraceLine {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (reg_we, reg, reg_value) = match self.reg {
+      Some((r, value)) => (1, r, value as u16),
+      None => (0, 0, 0)
+    };
+    let (nzp_we, nzp) = match self.nzp {
+      Some(nzp) => (1, nzp),
+      None => (0, 0)
+    };
+    let (data_we, data_addr, data_value) = match self.data {
+      Some((we, addr, value)) => (we as u8, addr, value as u16),
+      None => (0, 0, 0)
+    };
+    write!(f, "{:04X} {:016b} {} {} {:04X} {} {} {} {:04X} {:04X}",
+           self.pc, self.insn, reg_we, reg, reg_value,
+           nzp_we, nzp, data_we, data_addr, data_value)
+  }
+}
+
+// Steps the CPU, returning the trace of the instruction it executed
+pub fn trace_step(cpu: &mut CPU) -> Result<TraceLine, CPUError> {
+  let pc = cpu.pc;
+  let insn = cpu.memory[pc as usize] as u16;
+  try!(cpu.step());
+  Ok(TraceLine::retired(pc, insn, cpu))
+}
+
+#[derive(Debug)]
+pub enum TraceError { IoError(io::Error), ParseError(usize) }
+
+impl From<io::Error> for TraceError {
+  fn from(err: io::Error) -> TraceError {
+    TraceError::IoError(err)
+  }
+}
+
+// Blank lines are skipped; parse errors report the 1-based line number
+pub fn read_trace_file(filename: &str) -> Result<Vec<TraceLine>, TraceError> {
+  let file = try!(File::open(&Path::new(filename)));
+  let mut lines = Vec::new();
+  for (n, line) in BufReader::new(file).lines().enumerate() {
+    let line = try!(line);
+    if line.trim().is_empty() { continue }
+    match TraceLine::parse(&line) {
+      Some(traced) => lines.push(traced),
+      None => return Err(TraceError::ParseError(n + 1))
+    }
+  }
+  Ok(lines)
+}
+
+// Register and NZP state rebuilt from the writes in a trace.  Registers
+// never written in the trace are unknown.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceState {
+  pub regfile: [Option<i16>; 8],
+  pub nzp: Option<CC>
+}
+
+pub fn replay(lines: &[TraceLine]) -> TraceState {
+  let mut state = TraceState{ regfile: [None; 8], nzp: None };
+  for line in lines.iter() {
+    if let Some((r, value)) = line.reg { state.regfile[r] = Some(value) }
+    if let Some(nzp) = line.nzp { state.nzp = Some(nzp) }
+  }
+  state
+}
+
+// The first instruction at which two traces disagree, with the state each
+// trace had built up before it.  A missing line means that trace ended.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+  pub index: usize,
+  pub ours: Option<TraceLine>,
+  pub theirs: Option<TraceLine>,
+  pub our_state: TraceState,
+  pub their_state: TraceState
+}
+
+pub fn diff_traces(ours: &[TraceLine], theirs: &[TraceLine]) -> Option<Divergence> {
+  let len = if ours.len() > theirs.len() { ours.len() } else { theirs.len() };
+  (0..len).find(|&i| match (ours.get(i), theirs.get(i)) {
+    (Some(a), Some(b)) => !a.agrees(b),
+    _ => true
+  }).map(|i| Divergence{
+    index: i,
+    ours: ours.get(i).cloned(),
+    theirs: theirs.get(i).cloned(),
+    our_state: replay(&ours[..i]),
+    their_state: replay(&theirs[..i])
+  })
+}
+
+#[test]
+fn trace_unit_tests () {
+  // CONST R6, 0; HICONST R6, x20; STR R6, R6, 1; LDR R2, R6, 1; CMPI R2, 0
+  let mut cpu = boot_program(&[0x9C00, 0xDD20, 0x7D81, 0x6581, 0x2500]);
+
+  let lines: Vec<String> = (0..5).map(|_| trace_step(&mut cpu).unwrap().to_string()).collect();
+  assert!(lines[0] == "0000 1001110000000000 1 6 0000 1 2 0 0000 0000");
+  assert!(lines[1] == "0001 1101110100100000 1 6 2000 1 1 0 0000 0000");
+  assert!(lines[2] == "0002 0111110110000001 0 0 0000 0 0 1 2001 2000");
+  assert!(lines[3] == "0003 0110010110000001 1 2 2000 1 1 0 2001 2000");
+  assert!(lines[4] == "0004 0010010100000000 0 0 0000 1 1 0 0000 0000");
+}
+
+#[test]
+fn trace_diff_unit_tests () {
+  let ours: Vec<TraceLine> = [
+    "0000 1001001000000101 1 1 0005 1 1 0 0000 0000",
+    "0001 0001001001111111 1 1 0004 1 1 0 0000 0000",
+    "0002 0110010110000001 1 2 0007 1 1 0 2001 0007"
+  ].iter().map(|l| TraceLine::parse(l).unwrap()).collect();
+  let mut theirs = ours.clone();
+  assert!(ours[0].to_string() == "0000 1001001000000101 1 1 0005 1 1 0 0000 0000");
+  assert!(TraceLine::parse("0000 1001001000000101 1 9 0005 1 1 0 0000 0000") == None);
+  assert!(TraceLine::parse("0000 100100100000 1 1") == None);
+  assert!(diff_traces(&ours, &theirs) == None);
+
+  // Load details alone do not diverge
+  theirs[2].data = None;
+  assert!(diff_traces(&ours, &theirs) == None);
+
+  theirs[1].reg = Some((R1, 3));
+  let divergence = diff_traces(&ours, &theirs).unwrap();
+  assert!(divergence.index == 1);
+  assert!(divergence.our_state.regfile[R1] == Some(5));
+  assert!(divergence.our_state.regfile[R2] == None);
+  assert!(divergence.their_state.nzp == Some(P));
+
+  let divergence = diff_traces(&ours, &ours[..2]).unwrap();
+  assert!(divergence.index == 2 && divergence.theirs == None);
+}
@@ -0,0 +1,184 @@
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use assm_data::*;
+use bus::*;
+
+// The framebuffer maps one word per pixel, row-major, as 5-5-5 RGB
+pub const VIDEO_START : u16 = 0xC000;
+pub const VIDEO_END : u16 = 0xFDFF;
+pub const VIDEO_WIDTH : usize = 128;
+pub const VIDEO_HEIGHT : usize = 124;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImageFormat { PPM, PNG }
+
+impl ImageFormat {
+  pub fn for_path(path: &str) -> ImageFormat {
+    match Path::new(path).extension().and_then(|e| e.to_str()) {
+      Some("ppm") => ImageFormat::PPM,
+      _ => ImageFormat::PNG
+    }
+  }
+}
+
+fn channel(pixel: i16, shift: u16) -> u8 {
+  let c = ((pixel as u16) >> shift) & 0x1F;
+  ((c << 3) | (c >> 2)) as u8
+}
+
+// 8-bit RGB triples for every pixel of the framebuffer
+pub fn frame(memory: &Memory<i16>) -> Vec<u8> {
+  let mut rgb = Vec::with_capacity(VIDEO_WIDTH * VIDEO_HEIGHT * 3);
+  for addr in (VIDEO_START as usize) .. (VIDEO_END as usize + 1) {
+    let pixel = memory[addr];
+    rgb.push(channel(pixel, 10));
+    rgb.push(channel(pixel, 5));
+    rgb.push(channel(pixel, 0));
+  }
+  rgb
+}
+
+pub fn write_ppm(memory: &Memory<i16>, out: &mut Write) -> Result<(), io::Error> {
+  try!(write!(out, "P6\n{} {}\n255\n", VIDEO_WIDTH, VIDEO_HEIGHT));
+  out.write_all(&frame(memory))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = 0xFFFFFFFFu32;
+  for &b in bytes.iter() {
+    crc ^= b as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 }
+    }
+  }
+  !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for &byte in bytes.iter() {
+    a = (a + byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  (b << 16) | a
+}
+
+fn write_chunk(out: &mut Write, kind: &[u8], data: &[u8]) -> Result<(), io::Error> {
+  try!(out.write_u32::<BigEndian>(data.len() as u32));
+  let mut body = kind.to_vec();
+  body.extend(data.iter().cloned());
+  try!(out.write_all(&body));
+  out.write_u32::<BigEndian>(crc32(&body))
+}
+
+// Uncompressed PNG: the zlib stream uses stored deflate blocks only
+pub fn write_png(memory: &Memory<i16>, out: &mut Write) -> Result<(), io::Error> {
+  let rgb = frame(memory);
+  let mut raw = Vec::with_capacity(rgb.len() + VIDEO_HEIGHT);
+  for row in rgb.chunks(VIDEO_WIDTH * 3) {
+    raw.push(0);
+    raw.extend(row.iter().cloned());
+  }
+
+  let mut header = Vec::new();
+  try!(header.write_u32::<BigEndian>(VIDEO_WIDTH as u32));
+  try!(header.write_u32::<BigEndian>(VIDEO_HEIGHT as u32));
+  header.extend([8, 2, 0, 0, 0].iter().cloned());
+
+  let mut zlib = vec![0x78, 0x01];
+  let blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+  for (i, block) in blocks.iter().enumerate() {
+    zlib.push(if i + 1 == blocks.len() { 1 } else { 0 });
+    try!(zlib.write_u16::<LittleEndian>(block.len() as u16));
+    try!(zlib.write_u16::<LittleEndian>(!(block.len() as u16)));
+    zlib.extend(block.iter().cloned());
+  }
+  try!(zlib.write_u32::<BigEndian>(adler32(&raw)));
+
+  try!(out.write_all(b"\x89PNG\r\n\x1a\n"));
+  try!(write_chunk(out, b"IHDR", &header));
+  try!(write_chunk(out, b"IDAT", &zlib));
+  write_chunk(out, b"IEND", &[])
+}
+
+pub fn save_frame(memory: &Memory<i16>, path: &str) -> Result<(), io::Error> {
+  let mut file = try!(File::create(&Path::new(path)));
+  match ImageFormat::for_path(path) {
+    ImageFormat::PPM => write_ppm(memory, &mut file),
+    ImageFormat::PNG => write_png(memory, &mut file)
+  }
+}
+
+// Saves a snapshot of the framebuffer once the CPU has executed the given
+// number of instructions.  The video device claims no registers.
+pub struct Video {
+  path: String,
+  after: u64,
+  executed: u64,
+  error: Option<io::Error>
+}
+
+impl Video {
+
+  pub fn new(path: &str, after: u64) -> Video {
+    Video{ path: path.to_string(), after: after, executed: 0, error: None }
+  }
+
+  pub fn error(&self) -> Option<&io::Error> {
+    self.error.as_ref()
+  }
+
+}
+
+impl Device for Video {
+
+  fn claims(&self, _addr: u16) -> bool { false }
+
+  fn load(&mut self, _addr: u16) -> i16 { 0 }
+
+  fn store(&mut self, _addr: u16, _value: i16) {}
+
+  fn tick(&mut self, memory: &Memory<i16>) {
+    self.executed += 1;
+    if self.executed == self.after {
+      if let Err(err) = save_frame(memory, &self.path) {
+        self.error = Some(err)
+      }
+    }
+  }
+
+  fn save(&self, out: &mut Write) -> Result<(), io::Error> {
+    out.write_u64::<BigEndian>(self.executed)
+  }
+
+  fn restore(&mut self, input: &mut Read) -> Result<(), io::Error> {
+    self.executed = try!(input.read_u64::<BigEndian>());
+    Ok(())
+  }
+
+}
+
+#[test]
+fn video_unit_tests () {
+  let mut memory: Memory<i16> = box [0;0x10000];
+  memory[VIDEO_START as usize] = 0x7C00;
+  memory[VIDEO_END as usize] = 0x001F;
+
+  let rgb = frame(&memory);
+  assert!(rgb.len() == VIDEO_WIDTH * VIDEO_HEIGHT * 3);
+  assert!(&rgb[0..3] == &[0xFF, 0, 0]);
+  assert!(&rgb[rgb.len() - 3..] == &[0, 0, 0xFF]);
+
+  let mut ppm = Vec::new();
+  write_ppm(&memory, &mut ppm).unwrap();
+  assert!(ppm.starts_with(b"P6\n128 124\n255\n"));
+
+  let mut png = Vec::new();
+  write_png(&memory, &mut png).unwrap();
+  assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+  assert!(crc32(b"IEND") == 0xAE426082);
+}